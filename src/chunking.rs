@@ -0,0 +1,671 @@
+use itertools::Itertools;
+use markdown::mdast::Node;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChunkingStrategy {
+    /// One chunk per sentence
+    Sentence,
+    /// One chunk per top-level block (paragraph, list, code block...)
+    #[default]
+    Paragraph,
+    /// One chunk per heading and everything until the next heading
+    Section,
+    /// One chunk for the whole note
+    Note,
+    /// Windows of `size` lines, each overlapping the previous one by `overlap` lines
+    SlidingWindow { size: usize, overlap: usize },
+}
+
+impl ChunkingStrategy {
+    pub fn all() -> Vec<ChunkingStrategy> {
+        vec![
+            ChunkingStrategy::Paragraph,
+            ChunkingStrategy::Sentence,
+            ChunkingStrategy::Section,
+            ChunkingStrategy::Note,
+            ChunkingStrategy::SlidingWindow {
+                size: 10,
+                overlap: 3,
+            },
+        ]
+    }
+}
+
+impl Display for ChunkingStrategy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChunkingStrategy::Sentence => write!(f, "sentence"),
+            ChunkingStrategy::Paragraph => write!(f, "paragraph"),
+            ChunkingStrategy::Section => write!(f, "section"),
+            ChunkingStrategy::Note => write!(f, "note"),
+            ChunkingStrategy::SlidingWindow { size, overlap } => {
+                write!(f, "sliding window ({} lines, {} overlap)", size, overlap)
+            }
+        }
+    }
+}
+
+/// How fenced code blocks are embedded
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CodeBlockMode {
+    /// Like any other text
+    #[default]
+    Embed,
+    /// Not at all
+    Skip,
+    /// As their own chunks, tagged with their language and kept out of prose chunks
+    Separate,
+    /// As their own chunks, embedding the prose right before and after them
+    Context,
+}
+
+impl CodeBlockMode {
+    pub fn all() -> Vec<CodeBlockMode> {
+        vec![
+            CodeBlockMode::Embed,
+            CodeBlockMode::Skip,
+            CodeBlockMode::Separate,
+            CodeBlockMode::Context,
+        ]
+    }
+}
+
+impl Display for CodeBlockMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CodeBlockMode::Embed => write!(f, "embed like prose"),
+            CodeBlockMode::Skip => write!(f, "skip"),
+            CodeBlockMode::Separate => write!(f, "separate chunks"),
+            CodeBlockMode::Context => write!(f, "separate chunks, embed surrounding prose"),
+        }
+    }
+}
+
+/// Containers that get split into one unit per child instead of being embedded whole
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct NestingRules {
+    /// Every list item (and nested list item) on its own
+    pub list_items: bool,
+    /// Every table row on its own
+    pub table_rows: bool,
+    /// Every paragraph inside a blockquote on its own
+    pub blockquotes: bool,
+    /// Every paragraph inside a footnote definition on its own
+    pub footnotes: bool,
+}
+
+impl Default for NestingRules {
+    fn default() -> Self {
+        Self {
+            list_items: true,
+            table_rows: true,
+            blockquotes: true,
+            footnotes: true,
+        }
+    }
+}
+
+/// A piece of a note that gets embedded. Lines and columns are 1-indexed, columns
+/// and offsets count bytes and the end is exclusive.
+#[derive(Debug)]
+pub struct Chunk {
+    pub start_line_no: usize,
+    pub start_col: usize,
+    pub start_offset: usize,
+    pub end_line_no: usize,
+    pub end_col: usize,
+    pub end_offset: usize,
+    pub breadcrumb: Option<String>,
+    /// Language of the code block this chunk is made of
+    pub lang: Option<String>,
+    pub text: String,
+}
+
+struct Block<'a> {
+    node: &'a Node,
+    start_offset: usize,
+    end_offset: usize,
+    breadcrumb: Option<String>,
+}
+
+/// Converts byte offsets into lines and columns
+struct LineIndex {
+    line_starts: Vec<usize>,
+}
+
+impl LineIndex {
+    fn new(content: &str) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(content.match_indices('\n').map(|(idx, _)| idx + 1))
+            .collect();
+        Self { line_starts }
+    }
+
+    fn point(&self, offset: usize) -> (usize, usize) {
+        let line = self.line_starts.partition_point(|start| *start <= offset);
+        (line, offset - self.line_starts[line - 1] + 1)
+    }
+
+    fn chunk(&self, start: usize, end: usize, breadcrumb: Option<String>, text: String) -> Chunk {
+        let (start_line_no, start_col) = self.point(start);
+        let (end_line_no, end_col) = self.point(end);
+        Chunk {
+            start_line_no,
+            start_col,
+            start_offset: start,
+            end_line_no,
+            end_col,
+            end_offset: end,
+            breadcrumb,
+            lang: None,
+            text,
+        }
+    }
+}
+
+/// Collects the units a node is split into according to the nesting rules
+fn units<'a>(node: &'a Node, rules: &NestingRules, out: &mut Vec<&'a Node>) {
+    let children = node.children().into_iter().flatten();
+    match node {
+        Node::List(_) if rules.list_items => children.for_each(|c| units(c, rules, out)),
+        Node::ListItem(_) if rules.list_items => {
+            // The item's own text is a unit, nested lists are split further
+            for child in children {
+                match child {
+                    Node::List(_) => units(child, rules, out),
+                    _ => out.push(child),
+                }
+            }
+        }
+        Node::Table(_) if rules.table_rows => out.extend(children),
+        Node::Blockquote(_) if rules.blockquotes => children.for_each(|c| units(c, rules, out)),
+        Node::FootnoteDefinition(_) if rules.footnotes => {
+            children.for_each(|c| units(c, rules, out))
+        }
+        _ => out.push(node),
+    }
+}
+
+fn text(node: &Node) -> String {
+    match node {
+        Node::TableRow(_) => node
+            .children()
+            .into_iter()
+            .flatten()
+            .map(|cell| cell.to_string())
+            .join(" | "),
+        _ => node.to_string(),
+    }
+}
+
+/// Splits the note into blocks and pairs each with the breadcrumb of the headings
+/// it lives under
+fn blocks<'a>(ast: &'a Node, rules: &NestingRules) -> Vec<Block<'a>> {
+    let mut headings: Vec<(u8, String)> = vec![];
+    let mut blocks = vec![];
+    for child in ast.children().into_iter().flatten() {
+        // Frontmatter is metadata, not part of the note
+        if matches!(child, Node::Yaml(_) | Node::Toml(_)) {
+            continue;
+        }
+        if let Node::Heading(heading) = child {
+            headings.retain(|(depth, _)| *depth < heading.depth);
+        }
+
+        let mut nodes = vec![];
+        units(child, rules, &mut nodes);
+        for node in nodes {
+            let Some(pos) = node.position() else {
+                continue;
+            };
+            blocks.push(Block {
+                node,
+                start_offset: pos.start.offset,
+                end_offset: pos.end.offset,
+                breadcrumb: breadcrumb(&headings),
+            });
+        }
+
+        if let Node::Heading(heading) = child {
+            headings.push((heading.depth, child.to_string()));
+        }
+    }
+    blocks
+}
+
+fn breadcrumb(headings: &[(u8, String)]) -> Option<String> {
+    if headings.is_empty() {
+        return None;
+    }
+    Some(headings.iter().map(|(_, title)| title).join(" > "))
+}
+
+/// Splits a parsed note into the chunks that get embedded
+pub fn chunk(
+    ast: &Node,
+    content: &str,
+    strategy: &ChunkingStrategy,
+    rules: &NestingRules,
+    code_blocks: &CodeBlockMode,
+) -> Vec<Chunk> {
+    let index = LineIndex::new(content);
+    // Frontmatter isn't part of the note, blank it out so strategies working on the
+    // raw text skip it like `blocks` does
+    let frontmatter = ast
+        .children()
+        .into_iter()
+        .flatten()
+        .filter(|node| matches!(node, Node::Yaml(_) | Node::Toml(_)))
+        .filter_map(|node| node.position())
+        .map(|pos| (pos.start.offset, pos.end.offset));
+    let content = &mask(content, frontmatter);
+    let (code, prose): (Vec<Block>, Vec<Block>) = blocks(ast, rules)
+        .into_iter()
+        .partition(|b| matches!(b.node, Node::Code(_)));
+
+    if *code_blocks == CodeBlockMode::Embed {
+        let mut blocks: Vec<Block> = code.into_iter().chain(prose).collect();
+        blocks.sort_by_key(|b| b.start_offset);
+        return by_strategy(&index, &blocks, content, strategy);
+    }
+
+    // Blank out code blocks so strategies working on the raw text skip them too,
+    // spaces keep every offset in place
+    let masked = mask(content, code.iter().map(|b| (b.start_offset, b.end_offset)));
+    let mut chunks = by_strategy(&index, &prose, &masked, strategy);
+    if *code_blocks == CodeBlockMode::Skip {
+        return chunks;
+    }
+
+    chunks.extend(code.iter().map(|block| {
+        let text = match code_blocks {
+            // Without prose around it, the code is all there is to embed
+            CodeBlockMode::Context => {
+                surrounding_text(block, &prose).unwrap_or_else(|| text(block.node))
+            }
+            _ => text(block.node),
+        };
+        let breadcrumb = block.breadcrumb.clone();
+        let mut chunk = index.chunk(block.start_offset, block.end_offset, breadcrumb, text);
+        chunk.lang = lang(block.node);
+        chunk
+    }));
+    chunks.sort_by_key(|c| c.start_offset);
+    chunks
+}
+
+fn by_strategy(
+    index: &LineIndex,
+    blocks: &[Block],
+    content: &str,
+    strategy: &ChunkingStrategy,
+) -> Vec<Chunk> {
+    match strategy {
+        ChunkingStrategy::Sentence => sentences(index, blocks, content),
+        ChunkingStrategy::Paragraph => paragraphs(index, blocks),
+        ChunkingStrategy::Section => sections(index, blocks),
+        ChunkingStrategy::Note => note(index, blocks),
+        ChunkingStrategy::SlidingWindow { size, overlap } => {
+            sliding_window(index, blocks, content, *size, *overlap)
+        }
+    }
+}
+
+fn lang(node: &Node) -> Option<String> {
+    match node {
+        Node::Code(code) => code.lang.clone(),
+        _ => None,
+    }
+}
+
+/// Replaces everything in the byte ranges but line breaks with spaces
+fn mask(content: &str, ranges: impl IntoIterator<Item = (usize, usize)>) -> String {
+    let mut bytes = content.as_bytes().to_vec();
+    for (start, end) in ranges {
+        for byte in &mut bytes[start..end] {
+            if *byte != b'\n' {
+                *byte = b' ';
+            }
+        }
+    }
+    String::from_utf8(bytes).expect("Masked content should be ASCII where it changed")
+}
+
+/// The prose right before and after a code block, which usually explains it
+fn surrounding_text(code: &Block, prose: &[Block]) -> Option<String> {
+    let before = prose
+        .iter()
+        .rev()
+        .find(|b| b.end_offset <= code.start_offset);
+    let after = prose.iter().find(|b| b.start_offset >= code.end_offset);
+    let text = before
+        .into_iter()
+        .chain(after)
+        .filter(|b| !matches!(b.node, Node::Heading(_)))
+        .map(|b| text(b.node))
+        .join("\n");
+    (!text.is_empty()).then_some(text)
+}
+
+fn merge(index: &LineIndex, blocks: &[&Block], breadcrumb: Option<String>) -> Option<Chunk> {
+    let first = blocks.first()?;
+    let last = blocks.last()?;
+    let text = blocks.iter().map(|b| text(b.node)).join("\n");
+    let mut chunk = index.chunk(first.start_offset, last.end_offset, breadcrumb, text);
+    if blocks.len() == 1 {
+        chunk.lang = lang(first.node);
+    }
+    Some(chunk)
+}
+
+fn paragraphs(index: &LineIndex, blocks: &[Block]) -> Vec<Chunk> {
+    blocks
+        .iter()
+        .filter_map(|b| merge(index, &[b], b.breadcrumb.clone()))
+        .collect()
+}
+
+fn sections(index: &LineIndex, blocks: &[Block]) -> Vec<Chunk> {
+    let mut sections: Vec<Vec<&Block>> = vec![];
+    for block in blocks {
+        match sections.last_mut() {
+            Some(section) if !matches!(block.node, Node::Heading(_)) => section.push(block),
+            _ => sections.push(vec![block]),
+        }
+    }
+    sections
+        .iter()
+        .filter_map(|section| merge(index, section, section[0].breadcrumb.clone()))
+        .collect()
+}
+
+fn note(index: &LineIndex, blocks: &[Block]) -> Vec<Chunk> {
+    let all = blocks.iter().collect::<Vec<_>>();
+    merge(index, &all, None).into_iter().collect()
+}
+
+fn is_sentence_end(text: &str, idx: usize) -> bool {
+    let bytes = text.as_bytes();
+    match bytes[idx] {
+        b'.' | b'!' | b'?' => bytes.get(idx + 1).is_none_or(u8::is_ascii_whitespace),
+        b'\n' => bytes.get(idx + 1).is_none_or(|b| *b == b'\n'),
+        _ => false,
+    }
+}
+
+fn sentences(index: &LineIndex, blocks: &[Block], content: &str) -> Vec<Chunk> {
+    let mut chunks = vec![];
+    for block in blocks {
+        let Some(source) = content.get(block.start_offset..block.end_offset) else {
+            continue;
+        };
+
+        let mut start = 0;
+        for idx in 0..source.len() {
+            if idx + 1 != source.len() && !is_sentence_end(source, idx) {
+                continue;
+            }
+            let sentence = &source[start..=idx];
+            let text = sentence.trim();
+            if !text.is_empty() {
+                let leading = sentence.len() - sentence.trim_start().len();
+                let start_offset = block.start_offset + start + leading;
+                chunks.push(index.chunk(
+                    start_offset,
+                    start_offset + text.len(),
+                    block.breadcrumb.clone(),
+                    text.to_string(),
+                ));
+            }
+            start = idx + 1;
+        }
+    }
+    chunks
+}
+
+fn sliding_window(
+    index: &LineIndex,
+    blocks: &[Block],
+    content: &str,
+    size: usize,
+    overlap: usize,
+) -> Vec<Chunk> {
+    let lines: Vec<&str> = content.lines().collect();
+    let size = size.max(1);
+    let step = size.saturating_sub(overlap).max(1);
+
+    let mut chunks = vec![];
+    let mut start = 0;
+    while start < lines.len() {
+        let end = (start + size).min(lines.len());
+        let window = &lines[start..end];
+
+        // Trim blank lines so the range points at actual content
+        let first = window.iter().position(|l| !l.trim().is_empty());
+        let last = window.iter().rposition(|l| !l.trim().is_empty());
+        if let (Some(first), Some(last)) = (first, last) {
+            let start_offset = index.line_starts[start + first];
+            let end_offset = index.line_starts[start + last] + window[last].len();
+            let breadcrumb = blocks
+                .iter()
+                .find(|b| b.end_offset > start_offset)
+                .and_then(|b| b.breadcrumb.clone());
+            let text = window[first..=last].join("\n");
+            chunks.push(index.chunk(start_offset, end_offset, breadcrumb, text));
+        }
+
+        if end == lines.len() {
+            break;
+        }
+        start += step;
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOTE: &str =
+        "# Deploy\n\nBuild the image. Push it.\n\n## Rollback\n\nRevert the tag.\nRedeploy.\n";
+
+    fn parse(content: &str) -> Node {
+        let mut opts = markdown::ParseOptions::gfm();
+        opts.constructs.frontmatter = true;
+        markdown::to_mdast(content, &opts).unwrap()
+    }
+
+    fn chunks(content: &str, strategy: &ChunkingStrategy) -> Vec<Chunk> {
+        let ast = parse(content);
+        let rules = NestingRules::default();
+        chunk(&ast, content, strategy, &rules, &CodeBlockMode::Embed)
+    }
+
+    fn lines(content: &str, strategy: &ChunkingStrategy) -> Vec<(usize, usize)> {
+        chunks(content, strategy)
+            .iter()
+            .map(|c| (c.start_line_no, c.end_line_no))
+            .collect()
+    }
+
+    #[test]
+    fn strategies() {
+        assert_eq!(
+            lines(NOTE, &ChunkingStrategy::Sentence),
+            [(1, 1), (3, 3), (3, 3), (5, 5), (7, 7), (8, 8)]
+        );
+        assert_eq!(
+            lines(NOTE, &ChunkingStrategy::Paragraph),
+            [(1, 1), (3, 3), (5, 5), (7, 8)]
+        );
+        assert_eq!(lines(NOTE, &ChunkingStrategy::Section), [(1, 3), (5, 8)]);
+        assert_eq!(lines(NOTE, &ChunkingStrategy::Note), [(1, 8)]);
+    }
+
+    #[test]
+    fn sections_merge_under_their_heading() {
+        let sections = chunks(NOTE, &ChunkingStrategy::Section);
+        assert_eq!(sections[0].text, "Deploy\nBuild the image. Push it.");
+        assert_eq!(sections[1].text, "Rollback\nRevert the tag.\nRedeploy.");
+        assert_eq!(sections[1].breadcrumb.as_deref(), Some("Deploy"));
+
+        let paragraphs = chunks(NOTE, &ChunkingStrategy::Paragraph);
+        assert_eq!(paragraphs[3].text, "Revert the tag.\nRedeploy.");
+        assert_eq!(
+            paragraphs[3].breadcrumb.as_deref(),
+            Some("Deploy > Rollback")
+        );
+    }
+
+    #[test]
+    fn sliding_window_overlap() {
+        let strategy = ChunkingStrategy::SlidingWindow {
+            size: 3,
+            overlap: 1,
+        };
+        assert_eq!(lines(NOTE, &strategy), [(1, 3), (3, 5), (5, 7), (7, 8)]);
+    }
+
+    #[test]
+    fn frontmatter_is_not_embedded() {
+        let content = "---\ntags: [a]\n---\n# Deploy\n\nBuild it.\n";
+        let strategy = ChunkingStrategy::SlidingWindow {
+            size: 3,
+            overlap: 0,
+        };
+        let windows = chunks(content, &strategy);
+        assert_eq!(windows.len(), 1);
+        assert_eq!(windows[0].text, "# Deploy\n\nBuild it.");
+        assert_eq!((windows[0].start_line_no, windows[0].end_line_no), (4, 6));
+
+        assert_eq!(
+            lines(content, &ChunkingStrategy::Paragraph),
+            [(4, 4), (6, 6)]
+        );
+        assert_eq!(lines(content, &ChunkingStrategy::Note), [(4, 6)]);
+    }
+
+    const NOTHING_NESTED: NestingRules = NestingRules {
+        list_items: false,
+        table_rows: false,
+        blockquotes: false,
+        footnotes: false,
+    };
+
+    /// The paragraph chunks with their lines and text
+    fn units(content: &str, rules: &NestingRules) -> Vec<(usize, usize, String)> {
+        let ast = parse(content);
+        let strategy = ChunkingStrategy::Paragraph;
+        chunk(&ast, content, &strategy, rules, &CodeBlockMode::Embed)
+            .into_iter()
+            .map(|c| (c.start_line_no, c.end_line_no, c.text))
+            .collect()
+    }
+
+    fn spans(content: &str, rules: &NestingRules) -> Vec<(usize, usize)> {
+        units(content, rules)
+            .into_iter()
+            .map(|(start, end, _)| (start, end))
+            .collect()
+    }
+
+    #[test]
+    fn list_items() {
+        let content = "- one\n- two\n  - nested\n";
+        let rules = NestingRules {
+            list_items: true,
+            ..NOTHING_NESTED
+        };
+        assert_eq!(
+            units(content, &rules),
+            [
+                (1, 1, "one".to_string()),
+                (2, 2, "two".to_string()),
+                (3, 3, "nested".to_string())
+            ]
+        );
+        assert_eq!(spans(content, &NOTHING_NESTED), [(1, 3)]);
+    }
+
+    #[test]
+    fn table_rows() {
+        let content = "| a | b |\n| - | - |\n| 1 | 2 |\n";
+        let rules = NestingRules {
+            table_rows: true,
+            ..NOTHING_NESTED
+        };
+        assert_eq!(
+            units(content, &rules),
+            [(1, 1, "a | b".to_string()), (3, 3, "1 | 2".to_string())]
+        );
+        assert_eq!(spans(content, &NOTHING_NESTED), [(1, 3)]);
+    }
+
+    #[test]
+    fn blockquote_paragraphs() {
+        let content = "> first\n>\n> second\n";
+        let rules = NestingRules {
+            blockquotes: true,
+            ..NOTHING_NESTED
+        };
+        assert_eq!(
+            units(content, &rules),
+            [(1, 1, "first".to_string()), (3, 3, "second".to_string())]
+        );
+        assert_eq!(spans(content, &NOTHING_NESTED), [(1, 3)]);
+    }
+
+    #[test]
+    fn footnote_paragraphs() {
+        let content = "Text[^1]\n\n[^1]: First.\n\n    Second.\n";
+        let rules = NestingRules {
+            footnotes: true,
+            ..NOTHING_NESTED
+        };
+        let footnote: Vec<_> = units(content, &rules).into_iter().skip(1).collect();
+        assert_eq!(
+            footnote,
+            [(3, 3, "First.".to_string()), (5, 5, "Second.".to_string())]
+        );
+        assert_eq!(spans(content, &NOTHING_NESTED), [(1, 1), (3, 5)]);
+    }
+
+    #[test]
+    fn crlf_lines() {
+        let index = LineIndex::new("ab\r\ncd\r\n");
+        assert_eq!(index.point(0), (1, 1));
+        assert_eq!(index.point(2), (1, 3));
+        assert_eq!(index.point(4), (2, 1));
+        assert_eq!(index.point(6), (2, 3));
+
+        let content = "# Title\r\n\r\nSome text\r\n";
+        let paragraph = &chunks(content, &ChunkingStrategy::Paragraph)[1];
+        assert_eq!(paragraph.text, "Some text");
+        assert_eq!((paragraph.start_line_no, paragraph.start_col), (3, 1));
+        assert_eq!((paragraph.end_line_no, paragraph.end_col), (3, 10));
+        assert_eq!(paragraph.start_offset, 11);
+        assert_eq!(paragraph.end_offset, 20);
+    }
+
+    #[test]
+    fn multibyte_lines() {
+        // Columns and offsets count bytes, `ï` and `é` take two each
+        let content = "# Naïve\n\nUn café. Deux.\n";
+        let sentences = chunks(content, &ChunkingStrategy::Sentence);
+        let second = &sentences[2];
+        assert_eq!(second.text, "Deux.");
+        assert_eq!((second.start_line_no, second.start_col), (3, 11));
+        assert_eq!((second.end_line_no, second.end_col), (3, 16));
+        assert_eq!(&content[second.start_offset..second.end_offset], "Deux.");
+
+        let heading = &sentences[0];
+        assert_eq!(heading.end_col, 9);
+        assert_eq!(
+            &content[heading.start_offset..heading.end_offset],
+            "# Naïve"
+        );
+    }
+}
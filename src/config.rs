@@ -2,9 +2,17 @@ use anyhow::Result;
 use config::{Config, File, FileFormat};
 use inquire::ui::{Color, RenderConfig, StyleSheet, Styled};
 use serde::{Deserialize, Serialize};
-use std::{env, fs::OpenOptions, io::Write, path::PathBuf};
+use std::{
+    env,
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+};
 
-use crate::embeddings::ModelType;
+use crate::{
+    chunking::{ChunkingStrategy, CodeBlockMode, NestingRules},
+    embeddings::ModelType,
+};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ServerConfig {
@@ -33,16 +41,146 @@ impl ModelConfig {
     }
 }
 
+/// A cross-encoder re-scoring the best results against the query
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RerankerConfig {
+    /// Re-rank every query rather than only the ones asking for it
+    pub enabled: bool,
+    /// Hugging Face repo of a BERT cross-encoder with `rust_model.ot` weights
+    pub model: String,
+    /// Directory with `config.json`, `vocab.txt` and `rust_model.ot` to use instead of `model`
+    pub path: Option<PathBuf>,
+    /// How many of the best results get re-scored
+    pub candidates: usize,
+}
+
+impl Default for RerankerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            model: "cross-encoder/ms-marco-MiniLM-L-6-v2".to_string(),
+            path: None,
+            candidates: 30,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DirectoryChunkingConfig {
+    /// Directory the strategy applies to, relative to its data root or absolute
+    pub path: PathBuf,
+    #[serde(with = "serde_yaml::with::singleton_map")]
+    pub strategy: ChunkingStrategy,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ChunkingConfig {
+    /// Prepend the heading breadcrumb (e.g. "Deployment > Rollback") to the embedded text
+    pub breadcrumbs: bool,
+    #[serde(with = "serde_yaml::with::singleton_map")]
+    pub strategy: ChunkingStrategy,
+    /// Per-directory overrides of `strategy`, the most specific directory wins
+    pub directories: Vec<DirectoryChunkingConfig>,
+    /// Which containers (lists, tables...) are split into smaller chunks
+    pub nested: NestingRules,
+    pub code_blocks: CodeBlockMode,
+}
+
+impl Default for ChunkingConfig {
+    fn default() -> Self {
+        Self {
+            breadcrumbs: true,
+            strategy: ChunkingStrategy::default(),
+            directories: vec![],
+            nested: NestingRules::default(),
+            code_blocks: CodeBlockMode::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct IgnoreConfig {
+    /// Honor `.gitignore` and `.mindmapignore` files
+    pub ignore_files: bool,
+    /// Globs, relative to the data root, a file must match to be indexed
+    pub include: Vec<String>,
+    /// Globs, relative to the data root, of files and directories that are never indexed
+    pub exclude: Vec<String>,
+}
+
+impl Default for IgnoreConfig {
+    fn default() -> Self {
+        Self {
+            ignore_files: true,
+            include: vec!["**/*.md".to_string()],
+            exclude: vec![
+                "**/.git".to_string(),
+                "**/.obsidian".to_string(),
+                "**/.trash".to_string(),
+                "**/node_modules".to_string(),
+            ],
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RootConfig {
+    pub path: PathBuf,
+    /// Index the contents of symlinked files and directories
+    #[serde(default)]
+    pub follow_symlinks: bool,
+    /// Replaces the global `ignore` rules for this root
+    #[serde(default)]
+    pub ignore: Option<IgnoreConfig>,
+    /// Tag stored with every block indexed from this root
+    #[serde(default)]
+    pub collection: Option<String>,
+}
+
+impl RootConfig {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            follow_symlinks: false,
+            ignore: None,
+            collection: None,
+        }
+    }
+}
+
+/// Either a single notes directory or a list of roots with their own options
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum DataDir {
+    Single(PathBuf),
+    Roots(Vec<RootConfig>),
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct MindmapConfig {
-    pub data_dir: PathBuf,
+    pub data_dir: DataDir,
     pub db_path: PathBuf,
     pub log_path: PathBuf,
     pub min_score: f32,
+    /// Cut results off where the score drops by more than this from one to the next
+    #[serde(default)]
+    pub score_drop: Option<f32>,
+    /// Re-rank results for diversity with MMR, from 0 (only diversity) to 1 (only relevance)
+    #[serde(default)]
+    pub mmr_lambda: Option<f32>,
     pub num_results: usize,
     pub server: ServerConfig,
     pub model: ModelConfig,
+    #[serde(default)]
+    pub reranker: RerankerConfig,
     pub watcher: WatcherConfig,
+    #[serde(default)]
+    pub chunking: ChunkingConfig,
+    #[serde(default)]
+    pub ignore: IgnoreConfig,
 }
 
 impl MindmapConfig {
@@ -74,6 +212,55 @@ impl MindmapConfig {
         Ok(mindmap_config)
     }
 
+    pub fn get_roots(&self) -> Vec<RootConfig> {
+        match &self.data_dir {
+            DataDir::Single(path) => vec![RootConfig::new(path.clone())],
+            DataDir::Roots(roots) => roots.clone(),
+        }
+    }
+
+    /// The most specific root containing a file. Files outside every root are treated
+    /// as if their directory was a root with the global settings.
+    pub fn get_root(&self, file: &Path) -> RootConfig {
+        self.get_roots()
+            .into_iter()
+            .filter(|root| file.starts_with(&root.path))
+            .max_by_key(|root| root.path.components().count())
+            .unwrap_or_else(|| {
+                RootConfig::new(file.parent().unwrap_or(Path::new("")).to_path_buf())
+            })
+    }
+
+    pub fn get_ignore<'a>(&'a self, root: &'a RootConfig) -> &'a IgnoreConfig {
+        root.ignore.as_ref().unwrap_or(&self.ignore)
+    }
+
+    /// A fingerprint of the settings deciding what gets embedded, the model and the
+    /// chunking. Embeddings made with different ones can't share an index.
+    pub fn index_fingerprint(&self) -> String {
+        let settings = serde_json::to_string(&(&self.model.model, &self.chunking))
+            .expect("Settings should serialize");
+        // FNV-1a, which unlike `DefaultHasher` stays the same across builds
+        let hash = settings
+            .bytes()
+            .fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+                (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+            });
+        format!("{:016x}", hash)
+    }
+
+    pub fn get_chunking_strategy(&self, file: &Path) -> &ChunkingStrategy {
+        let root = self.get_root(file);
+        self.chunking
+            .directories
+            .iter()
+            .map(|dir| (root.path.join(&dir.path), &dir.strategy))
+            .filter(|(dir, _)| file.starts_with(dir))
+            .max_by_key(|(dir, _)| dir.components().count())
+            .map(|(_, strategy)| strategy)
+            .unwrap_or(&self.chunking.strategy)
+    }
+
     pub fn load() -> Self {
         Self::try_load().unwrap_or_else(|_| Self::default())
     }
@@ -100,15 +287,18 @@ impl Default for MindmapConfig {
         let config = Self::get_config_dir().expect("Config directory should exist");
         let model = ModelType::AllMiniLmL12V2;
         let mindmap_config = Self {
-            data_dir: home.join("notes"),
+            data_dir: DataDir::Single(home.join("notes")),
             db_path: config.join("mindmap.db"),
             log_path: config.join("mindmap.log"),
             min_score: 0.25,
+            score_drop: None,
+            mmr_lambda: None,
             model: ModelConfig {
                 model,
                 remote: true,
                 dir: config.join("models/"),
             },
+            reranker: RerankerConfig::default(),
             num_results: 20,
             server: ServerConfig {
                 host: "127.0.0.1".to_string(),
@@ -118,6 +308,8 @@ impl Default for MindmapConfig {
             watcher: WatcherConfig {
                 lock_path: home.join(".mindmap-watcher.lock"),
             },
+            chunking: ChunkingConfig::default(),
+            ignore: IgnoreConfig::default(),
         };
         mindmap_config.save().expect("Config should save");
         mindmap_config
@@ -1,19 +1,54 @@
-use rusqlite::Connection;
+use rusqlite::{types::Value, Connection, OptionalExtension, Row, Transaction};
 use rust_bert::pipelines::sentence_embeddings::Embedding;
-use std::path::{Path, PathBuf};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
 use anyhow::Result;
 
-use crate::{config::MindmapConfig, server};
+use crate::{
+    config::MindmapConfig,
+    embeddings,
+    index::{IndexState, VectorIndex},
+    links::{Link, LinkKind},
+    search::SearchFilter,
+    server,
+};
 
 #[derive(Debug)]
 pub struct EmbeddedSentence {
     pub path: PathBuf,
+    pub collection: Option<String>,
     pub start_line_no: usize,
+    pub start_col: usize,
+    pub start_offset: usize,
     pub end_line_no: usize,
+    pub end_col: usize,
+    pub end_offset: usize,
+    pub breadcrumb: Option<String>,
+    pub lang: Option<String>,
     pub embedding: Embedding,
 }
 
+/// The state of a file when it was indexed, to tell which files changed since
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileStamp {
+    /// Milliseconds since the Unix epoch
+    pub modified: i64,
+    pub size: i64,
+}
+
+/// What is stored about a file besides its sentences and links
+#[derive(Debug, Clone)]
+pub struct FileRecord {
+    pub path: PathBuf,
+    pub stamp: FileStamp,
+    /// Milliseconds since the Unix epoch
+    pub created: Option<i64>,
+    pub tags: Vec<String>,
+}
+
 pub fn start(config: &MindmapConfig) -> Result<()> {
     let conn = Connection::open(&config.db_path)?;
     conn.execute(
@@ -25,9 +60,101 @@ pub fn start(config: &MindmapConfig) -> Result<()> {
         )",
         (),
     )?;
+    add_column_if_missing(&conn, "sentences", "breadcrumb", "TEXT")?;
+    add_column_if_missing(
+        &conn,
+        "sentences",
+        "start_col",
+        "INTEGER NOT NULL DEFAULT 1",
+    )?;
+    add_column_if_missing(&conn, "sentences", "end_col", "INTEGER NOT NULL DEFAULT 1")?;
+    add_column_if_missing(
+        &conn,
+        "sentences",
+        "start_offset",
+        "INTEGER NOT NULL DEFAULT 0",
+    )?;
+    add_column_if_missing(
+        &conn,
+        "sentences",
+        "end_offset",
+        "INTEGER NOT NULL DEFAULT 0",
+    )?;
+    add_column_if_missing(&conn, "sentences", "collection", "TEXT")?;
+    add_column_if_missing(&conn, "sentences", "lang", "TEXT")?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS links (
+            source TEXT,
+            target TEXT,
+            kind TEXT,
+            line_no INTEGER
+        )",
+        (),
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS links_source ON links (source)",
+        (),
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS files (
+            path TEXT PRIMARY KEY,
+            modified INTEGER,
+            size INTEGER
+        )",
+        (),
+    )?;
+    // Forgetting the stamps makes the next run reindex the files to fill the column in
+    if add_column_if_missing(&conn, "files", "created", "INTEGER")? {
+        conn.execute("DELETE FROM files", ())?;
+    }
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS tags (
+            path TEXT,
+            tag TEXT
+        )",
+        (),
+    )?;
+    conn.execute("CREATE INDEX IF NOT EXISTS tags_path ON tags (path)", ())?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS runs (
+            finished_at INTEGER,
+            chunks INTEGER,
+            elapsed_secs REAL
+        )",
+        (),
+    )?;
+    add_column_if_missing(&conn, "runs", "fingerprint", "TEXT")?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS generation (value INTEGER NOT NULL)",
+        (),
+    )?;
+    conn.execute(
+        "INSERT INTO generation (value) SELECT 0 WHERE NOT EXISTS (SELECT 1 FROM generation)",
+        (),
+    )?;
     Ok(())
 }
 
+/// Adds a column to a table of databases created by older versions, returns
+/// whether it was missing
+fn add_column_if_missing(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<bool> {
+    let exists = conn
+        .prepare("SELECT 1 FROM pragma_table_info(?1) WHERE name = ?2")?
+        .exists([table, column])?;
+    if !exists {
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+            (),
+        )?;
+    }
+    Ok(!exists)
+}
+
 fn u8_to_f32(bytes: &[u8]) -> Vec<f32> {
     bytes
         .chunks_exact(4)
@@ -41,57 +168,517 @@ fn f32_to_u8(floats: &[f32]) -> Vec<u8> {
     floats.iter().flat_map(|f| f.to_le_bytes()).collect()
 }
 
+/// Fewer than the 999 parameters older SQLite versions allow per statement
+const MAX_PARAMS: usize = 900;
+
+const SENTENCE_COLUMNS: &str = "path, start_line_no, start_col, start_offset, end_line_no, end_col, end_offset, breadcrumb, embedding, collection, lang";
+
+fn sentence_from_row(row: &Row) -> rusqlite::Result<EmbeddedSentence> {
+    let path = row.get::<_, String>(0)?;
+    let start_line_no = row.get::<_, usize>(1)?;
+    let start_col = row.get::<_, usize>(2)?;
+    let start_offset = row.get::<_, usize>(3)?;
+    let end_line_no = row.get::<_, usize>(4)?;
+    let end_col = row.get::<_, usize>(5)?;
+    let end_offset = row.get::<_, usize>(6)?;
+    let breadcrumb = row.get::<_, Option<String>>(7)?;
+    let embedding = row.get::<_, Vec<u8>>(8)?;
+    let collection = row.get::<_, Option<String>>(9)?;
+    let lang = row.get::<_, Option<String>>(10)?;
+
+    Ok(EmbeddedSentence {
+        path: PathBuf::from(path),
+        collection,
+        start_line_no,
+        start_col,
+        start_offset,
+        end_line_no,
+        end_col,
+        end_offset,
+        breadcrumb,
+        lang,
+        embedding: u8_to_f32(&embedding),
+    })
+}
+
 pub fn get_all(config: &MindmapConfig) -> Result<Vec<EmbeddedSentence>> {
     let conn = Connection::open(&config.db_path)?;
-    let mut stmt =
-        conn.prepare("SELECT path, start_line_no, end_line_no, embedding FROM sentences")?;
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM sentences", SENTENCE_COLUMNS))?;
+    let rows = stmt
+        .query_map([], sentence_from_row)?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(rows)
+}
+
+/// The sentences with the given rowids, e.g. the neighbors found in the index
+pub fn get_by_ids(ids: &[i64], config: &MindmapConfig) -> Result<HashMap<i64, EmbeddedSentence>> {
+    let conn = Connection::open(&config.db_path)?;
+    let mut rows = HashMap::new();
+    // SQLite limits how many parameters a statement takes
+    for ids in ids.chunks(MAX_PARAMS) {
+        let placeholders = vec!["?"; ids.len()].join(", ");
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {}, rowid FROM sentences WHERE rowid IN ({})",
+            SENTENCE_COLUMNS, placeholders
+        ))?;
+        let chunk = stmt.query_map(rusqlite::params_from_iter(ids), |row| {
+            Ok((row.get::<_, i64>(11)?, sentence_from_row(row)?))
+        })?;
+        for row in chunk {
+            let (id, sentence) = row?;
+            rows.insert(id, sentence);
+        }
+    }
+    Ok(rows)
+}
+
+/// Every embedding with its rowid, to build the index from
+pub fn get_embeddings(config: &MindmapConfig) -> Result<Vec<(i64, Embedding)>> {
+    let conn = Connection::open(&config.db_path)?;
+    let mut stmt = conn.prepare("SELECT rowid, embedding FROM sentences")?;
     let rows = stmt
         .query_map([], |row| {
-            let path = row.get::<_, String>(0)?;
-            let start_line_no = row.get::<_, usize>(1)?;
-            let end_line_no = row.get::<_, usize>(2)?;
-            let embedding = row.get::<_, Vec<u8>>(3)?;
-
-            Ok(EmbeddedSentence {
-                path: PathBuf::from(path),
-                start_line_no,
-                end_line_no,
-                embedding: u8_to_f32(&embedding),
-            })
-        })
+            let embedding = row.get::<_, Vec<u8>>(1)?;
+            Ok((row.get::<_, i64>(0)?, u8_to_f32(&embedding)))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(rows)
+}
+
+/// The embeddings of one file with their rowids
+pub fn get_file_embeddings(file: &Path, config: &MindmapConfig) -> Result<Vec<(i64, Embedding)>> {
+    let conn = Connection::open(&config.db_path)?;
+    let mut stmt = conn.prepare("SELECT rowid, embedding FROM sentences WHERE path = ?1")?;
+    let rows = stmt
+        .query_map(rusqlite::params![file.to_str()], |row| {
+            let embedding = row.get::<_, Vec<u8>>(1)?;
+            Ok((row.get::<_, i64>(0)?, u8_to_f32(&embedding)))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(rows)
+}
+
+/// Every note with the mean of its sentences' embeddings
+pub fn get_note_embeddings(config: &MindmapConfig) -> Result<Vec<(PathBuf, Embedding)>> {
+    let conn = Connection::open(&config.db_path)?;
+    let mut stmt = conn.prepare("SELECT path, embedding FROM sentences ORDER BY path")?;
+    let rows = stmt.query_map([], |row| {
+        let path = row.get::<_, String>(0)?;
+        Ok((PathBuf::from(path), u8_to_f32(&row.get::<_, Vec<u8>>(1)?)))
+    })?;
+
+    let mut notes = vec![];
+    let mut current: Option<(PathBuf, Vec<Embedding>)> = None;
+    for row in rows {
+        let (path, embedding) = row?;
+        match &mut current {
+            Some((current_path, embeddings)) if *current_path == path => embeddings.push(embedding),
+            _ => notes.extend(current.replace((path, vec![embedding]))),
+        }
+    }
+    notes.extend(current);
+
+    let means = notes
         .into_iter()
-        .flatten()
+        .filter_map(|(path, embeddings)| {
+            let rows: Vec<&Embedding> = embeddings.iter().collect();
+            Some((path, embeddings::mean(&rows)?))
+        })
+        .collect();
+    Ok(means)
+}
+
+/// Every sentence of a file with its rowid, in order
+pub fn get_file_sentences(
+    file: &Path,
+    config: &MindmapConfig,
+) -> Result<Vec<(i64, EmbeddedSentence)>> {
+    let conn = Connection::open(&config.db_path)?;
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {}, rowid FROM sentences WHERE path = ?1 ORDER BY start_offset",
+        SENTENCE_COLUMNS
+    ))?;
+    let rows = stmt
+        .query_map(rusqlite::params![file.to_str()], |row| {
+            Ok((row.get::<_, i64>(11)?, sentence_from_row(row)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(rows)
+}
+
+fn query_ids_by_path(
+    conn: &Connection,
+    sql: &str,
+    params: impl rusqlite::Params,
+) -> Result<HashMap<PathBuf, Vec<i64>>> {
+    let mut stmt = conn.prepare(sql)?;
+    let mut ids: HashMap<PathBuf, Vec<i64>> = HashMap::new();
+    let rows = stmt.query_map(params, |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            PathBuf::from(row.get::<_, String>(1)?),
+        ))
+    })?;
+    for row in rows {
+        let (id, path) = row?;
+        ids.entry(path).or_default().push(id);
+    }
+    Ok(ids)
+}
+
+/// The rowids of every file's sentences, only code blocks in `lang` if given
+pub fn get_ids_by_path(
+    lang: Option<&str>,
+    config: &MindmapConfig,
+) -> Result<HashMap<PathBuf, Vec<i64>>> {
+    let conn = Connection::open(&config.db_path)?;
+    query_ids_by_path(
+        &conn,
+        "SELECT rowid, path FROM sentences WHERE ?1 IS NULL OR lang = ?1",
+        [lang],
+    )
+}
+
+/// Like `get_ids_by_path`, for the files passing every filter but the path ones, which
+/// take globs
+pub fn get_filtered_ids_by_path(
+    filter: &SearchFilter,
+    config: &MindmapConfig,
+) -> Result<HashMap<PathBuf, Vec<i64>>> {
+    let mut conditions = vec!["1"];
+    let mut params: Vec<Value> = vec![];
+    if let Some(lang) = &filter.lang {
+        conditions.push("s.lang = ?");
+        params.push(lang.clone().into());
+    }
+    let tag = |tag: &String| tag.trim_start_matches('#').to_lowercase();
+    for included in &filter.tags {
+        conditions.push("EXISTS (SELECT 1 FROM tags t WHERE t.path = s.path AND t.tag = ?)");
+        params.push(tag(included).into());
+    }
+    for excluded in &filter.exclude_tags {
+        conditions.push("NOT EXISTS (SELECT 1 FROM tags t WHERE t.path = s.path AND t.tag = ?)");
+        params.push(tag(excluded).into());
+    }
+    let extensions = vec!["s.path LIKE ? ESCAPE '\\'"; filter.extensions.len()].join(" OR ");
+    if !filter.extensions.is_empty() {
+        conditions.push(&extensions);
+        for extension in &filter.extensions {
+            let extension = extension.trim_start_matches('.');
+            let escaped = extension
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_");
+            params.push(format!("%.{}", escaped).into());
+        }
+    }
+    // Files without a date never fall in a range, as NULL never compares true
+    let dates = [
+        ("f.modified >= ?", filter.modified_after),
+        ("f.modified < ?", filter.modified_before),
+        ("f.created >= ?", filter.created_after),
+        ("f.created < ?", filter.created_before),
+    ];
+    for (condition, date) in dates {
+        if let Some(date) = date {
+            conditions.push(condition);
+            params.push(date.into());
+        }
+    }
+
+    let conn = Connection::open(&config.db_path)?;
+    let sql = format!(
+        "SELECT s.rowid, s.path FROM sentences s LEFT JOIN files f ON f.path = s.path
+        WHERE ({})",
+        conditions.join(") AND (")
+    );
+    query_ids_by_path(&conn, &sql, rusqlite::params_from_iter(params))
+}
+
+fn index_state(conn: &Connection) -> Result<IndexState> {
+    let state = conn.query_row(
+        "SELECT (SELECT IFNULL(MAX(rowid), 0) FROM sentences), (SELECT value FROM generation)",
+        [],
+        |row| {
+            Ok(IndexState {
+                max_id: row.get(0)?,
+                generation: row.get(1)?,
+            })
+        },
+    )?;
+    Ok(state)
+}
+
+/// Where the sentences stand, for telling whether a saved index missed changes
+pub fn get_index_state(config: &MindmapConfig) -> Result<IndexState> {
+    let conn = Connection::open(&config.db_path)?;
+    index_state(&conn)
+}
+
+/// Marks a change to the sentences, so an index saved before it gets rebuilt
+fn bump_generation(conn: &Connection) -> Result<()> {
+    conn.execute("UPDATE generation SET value = value + 1", ())?;
+    Ok(())
+}
+
+/// Every note that has at least one indexed block
+pub fn get_paths(config: &MindmapConfig) -> Result<Vec<PathBuf>> {
+    let conn = Connection::open(&config.db_path)?;
+    let mut stmt = conn.prepare("SELECT DISTINCT path FROM sentences ORDER BY path")?;
+    let rows = stmt
+        .query_map([], |row| row.get::<_, String>(0).map(PathBuf::from))?
         .collect::<Result<Vec<_>, _>>()?;
     Ok(rows)
 }
 
-pub fn insert_many(embs: &Vec<EmbeddedSentence>, config: &MindmapConfig) -> Result<()> {
+pub fn get_stamps(config: &MindmapConfig) -> Result<HashMap<PathBuf, FileStamp>> {
+    let conn = Connection::open(&config.db_path)?;
+    let mut stmt = conn.prepare("SELECT path, modified, size FROM files")?;
+    let rows = stmt
+        .query_map([], |row| {
+            let stamp = FileStamp {
+                modified: row.get(1)?,
+                size: row.get(2)?,
+            };
+            Ok((PathBuf::from(row.get::<_, String>(0)?), stamp))
+        })?
+        .collect::<Result<HashMap<_, _>, _>>()?;
+    Ok(rows)
+}
+
+pub fn get_files(config: &MindmapConfig) -> Result<HashMap<PathBuf, FileRecord>> {
+    let conn = Connection::open(&config.db_path)?;
+    let mut stmt = conn.prepare("SELECT path, modified, size, created FROM files")?;
+    let mut files = stmt
+        .query_map([], |row| {
+            let path = PathBuf::from(row.get::<_, String>(0)?);
+            let file = FileRecord {
+                path: path.clone(),
+                stamp: FileStamp {
+                    modified: row.get(1)?,
+                    size: row.get(2)?,
+                },
+                created: row.get(3)?,
+                tags: vec![],
+            };
+            Ok((path, file))
+        })?
+        .collect::<Result<HashMap<_, _>, _>>()?;
+
+    let mut stmt = conn.prepare("SELECT path, tag FROM tags")?;
+    let tags = stmt.query_map([], |row| {
+        Ok((
+            PathBuf::from(row.get::<_, String>(0)?),
+            row.get::<_, String>(1)?,
+        ))
+    })?;
+    for tag in tags {
+        let (path, tag) = tag?;
+        if let Some(file) = files.get_mut(&path) {
+            file.tags.push(tag);
+        }
+    }
+    Ok(files)
+}
+
+/// Records how long a run took so the next one can be estimated, and the settings it
+/// embedded with
+pub fn insert_run(chunks: usize, elapsed_secs: f64, config: &MindmapConfig) -> Result<()> {
+    let conn = Connection::open(&config.db_path)?;
+    conn.execute(
+        "INSERT INTO runs (finished_at, chunks, elapsed_secs, fingerprint) VALUES (strftime('%s', 'now'), ?1, ?2, ?3)",
+        rusqlite::params![chunks, elapsed_secs, config.index_fingerprint()],
+    )?;
+    Ok(())
+}
+
+/// The fingerprint of the settings of the last run, if any run recorded one
+pub fn get_fingerprint(config: &MindmapConfig) -> Result<Option<String>> {
+    let conn = Connection::open(&config.db_path)?;
+    let fingerprint = conn
+        .query_row(
+            "SELECT fingerprint FROM runs WHERE fingerprint IS NOT NULL
+            ORDER BY finished_at DESC, rowid DESC LIMIT 1",
+            [],
+            |row| row.get::<_, String>(0),
+        )
+        .optional()?;
+    Ok(fingerprint)
+}
+
+/// Chunks indexed per second over the last few runs, if any
+pub fn get_throughput(config: &MindmapConfig) -> Result<Option<f64>> {
+    let conn = Connection::open(&config.db_path)?;
+    let throughput = conn.query_row(
+        "SELECT SUM(chunks) / SUM(elapsed_secs) FROM (
+            SELECT chunks, elapsed_secs FROM runs
+            WHERE chunks > 0 AND elapsed_secs > 0
+            ORDER BY finished_at DESC LIMIT 5
+        )",
+        [],
+        |row| row.get::<_, Option<f64>>(0),
+    )?;
+    Ok(throughput)
+}
+
+pub fn get_links(config: &MindmapConfig) -> Result<Vec<Link>> {
+    let conn = Connection::open(&config.db_path)?;
+    let mut stmt = conn.prepare("SELECT source, target, kind, line_no FROM links")?;
+    let rows = stmt
+        .query_map([], |row| {
+            let kind = row.get::<_, String>(2)?;
+            Ok(Link {
+                source: PathBuf::from(row.get::<_, String>(0)?),
+                target: row.get::<_, String>(1)?,
+                kind: kind.parse().unwrap_or(LinkKind::Wiki),
+                line_no: row.get::<_, usize>(3)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(rows)
+}
+
+fn insert_links(tx: &Transaction, links: &[Link]) -> Result<()> {
+    for link in links {
+        tx.execute(
+            "INSERT INTO links (source, target, kind, line_no) VALUES (?1, ?2, ?3, ?4)",
+            rusqlite::params![
+                link.source.to_str(),
+                link.target,
+                link.kind.to_string(),
+                link.line_no
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+/// Returns the rowids of the new rows
+fn insert_sentences(tx: &Transaction, embs: &[EmbeddedSentence]) -> Result<Vec<i64>> {
+    let mut ids = vec![];
+    for emb in embs {
+        tx.execute(
+            "INSERT INTO sentences (path, start_line_no, start_col, start_offset, end_line_no, end_col, end_offset, breadcrumb, embedding, collection, lang) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            rusqlite::params![
+                emb.path.to_str(),
+                emb.start_line_no,
+                emb.start_col,
+                emb.start_offset,
+                emb.end_line_no,
+                emb.end_col,
+                emb.end_offset,
+                emb.breadcrumb,
+                f32_to_u8(&emb.embedding),
+                emb.collection,
+                emb.lang
+            ],
+        )?;
+        ids.push(tx.last_insert_rowid());
+    }
+    Ok(ids)
+}
+
+/// Returns the rowids of the deleted sentences
+fn delete_rows(tx: &Transaction, file: &Path) -> Result<Vec<i64>> {
+    let ids = tx
+        .prepare("SELECT rowid FROM sentences WHERE path = ?1")?
+        .query_map(rusqlite::params![file.to_str()], |row| row.get(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    tx.execute(
+        "DELETE FROM sentences WHERE path = ?1",
+        rusqlite::params![file.to_str()],
+    )?;
+    tx.execute(
+        "DELETE FROM links WHERE source = ?1",
+        rusqlite::params![file.to_str()],
+    )?;
+    tx.execute(
+        "DELETE FROM files WHERE path = ?1",
+        rusqlite::params![file.to_str()],
+    )?;
+    tx.execute(
+        "DELETE FROM tags WHERE path = ?1",
+        rusqlite::params![file.to_str()],
+    )?;
+    Ok(ids)
+}
+
+/// Swaps the rows of the given files for new ones in a single transaction, so
+/// readers never see a half-indexed file, then updates the index to match. Saving
+/// the index and notifying the server is up to the caller.
+pub fn replace_files(
+    files: &[FileRecord],
+    embs: &[EmbeddedSentence],
+    links: &[Link],
+    index: &mut VectorIndex,
+    config: &MindmapConfig,
+) -> Result<()> {
+    index.sync(config)?;
     let mut conn = Connection::open(&config.db_path)?;
     let tx = conn.transaction()?;
-    for emb in embs {
+    let before = index_state(&tx)?;
+    let mut removed = vec![];
+    for file in files {
+        let path = file.path.to_str();
+        removed.extend(delete_rows(&tx, &file.path)?);
         tx.execute(
-            "INSERT INTO sentences (path, start_line_no, end_line_no, embedding) VALUES (?1, ?2, ?3, ?4)",
-            rusqlite::params![emb.path.to_str(), emb.start_line_no, emb.end_line_no, f32_to_u8(&emb.embedding)],
+            "INSERT INTO files (path, modified, size, created) VALUES (?1, ?2, ?3, ?4)",
+            rusqlite::params![path, file.stamp.modified, file.stamp.size, file.created],
         )?;
+        for tag in &file.tags {
+            tx.execute(
+                "INSERT INTO tags (path, tag) VALUES (?1, ?2)",
+                rusqlite::params![path, tag],
+            )?;
+        }
     }
+    let added = insert_sentences(&tx, embs)?;
+    insert_links(&tx, links)?;
+    bump_generation(&tx)?;
+    let after = index_state(&tx)?;
     tx.commit()?;
-    server::notify_rebuild(config).ok();
+
+    for id in removed {
+        index.remove(id)?;
+    }
+    for (id, emb) in added.into_iter().zip(embs) {
+        index.insert(id, &emb.embedding)?;
+    }
+    index.advance(before, after);
     Ok(())
 }
 
 pub fn delete_all(config: &MindmapConfig) -> Result<()> {
     let conn = Connection::open(&config.db_path)?;
     conn.execute("DELETE FROM sentences", [])?;
+    conn.execute("DELETE FROM links", [])?;
+    conn.execute("DELETE FROM files", [])?;
+    conn.execute("DELETE FROM tags", [])?;
+    bump_generation(&conn)?;
     server::notify_rebuild(config).ok();
     Ok(())
 }
 
-pub fn delete_file(file: &Path, config: &MindmapConfig) -> Result<()> {
-    let conn = Connection::open(&config.db_path)?;
-    conn.execute(
-        "DELETE FROM sentences WHERE path = ?1",
-        rusqlite::params![file.to_str()],
-    )?;
-    server::notify_rebuild(config).ok();
+pub fn delete_files(
+    files: &[PathBuf],
+    index: &mut VectorIndex,
+    config: &MindmapConfig,
+) -> Result<()> {
+    index.sync(config)?;
+    let mut conn = Connection::open(&config.db_path)?;
+    let tx = conn.transaction()?;
+    let before = index_state(&tx)?;
+    let mut removed = vec![];
+    for file in files {
+        removed.extend(delete_rows(&tx, file)?);
+    }
+    bump_generation(&tx)?;
+    let after = index_state(&tx)?;
+    tx.commit()?;
+
+    for id in removed {
+        index.remove(id)?;
+    }
+    index.advance(before, after);
     Ok(())
 }
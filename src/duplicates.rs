@@ -0,0 +1,236 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use anyhow::Result;
+use colored::Colorize;
+use rust_bert::pipelines::sentence_embeddings::Embedding;
+use serde::Serialize;
+
+use crate::{config::MindmapConfig, database, formatter::OutputFormat, index::VectorIndex};
+
+/// A block, or a whole note when comparing notes
+#[derive(Clone, Debug, Serialize)]
+pub struct DuplicateItem {
+    pub path: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_line_no: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end_line_no: Option<usize>,
+}
+
+/// Two items of a cluster, by their position in it
+#[derive(Debug, Serialize)]
+pub struct DuplicatePair {
+    pub first: usize,
+    pub second: usize,
+    pub score: f32,
+}
+
+/// Items linked by pairs scoring above the threshold
+#[derive(Debug, Serialize)]
+pub struct DuplicateCluster {
+    /// The score of its most similar pair
+    pub score: f32,
+    pub items: Vec<DuplicateItem>,
+    pub pairs: Vec<DuplicatePair>,
+}
+
+/// Two ids, the lowest first, and their score
+type ScoredPair = ((i64, i64), f32);
+
+/// Finds the set an item belongs to, joining sets along the way
+struct UnionFind {
+    parents: HashMap<i64, i64>,
+}
+
+impl UnionFind {
+    fn find(&mut self, id: i64) -> i64 {
+        let parent = *self.parents.entry(id).or_insert(id);
+        if parent == id {
+            return id;
+        }
+        let root = self.find(parent);
+        self.parents.insert(id, root);
+        root
+    }
+
+    fn union(&mut self, a: i64, b: i64) {
+        let (a, b) = (self.find(a), self.find(b));
+        self.parents.insert(a, b);
+    }
+}
+
+/// Every pair scoring at least `threshold`, looking at the `neighbors` closest items of other
+/// files for each one. Items of the same file are never paired, they overlap when chunks
+/// are nested.
+fn similar_pairs(
+    embeddings: &[(i64, Embedding)],
+    index: &VectorIndex,
+    paths: &HashMap<i64, PathBuf>,
+    neighbors: usize,
+    threshold: f32,
+) -> Result<HashMap<(i64, i64), f32>> {
+    let mut pairs = HashMap::new();
+    for (id, embedding) in embeddings {
+        // Leaving out the file's own items inside the search, so they don't take up
+        // the neighbors
+        let path = paths.get(id);
+        let others = index.search_where(embedding, neighbors, |other| paths.get(&other) != path)?;
+        for (other, distance) in others {
+            let score = 1.0 - distance;
+            if score < threshold {
+                continue;
+            }
+            pairs.insert((*id.min(&other), *id.max(&other)), score);
+        }
+    }
+    Ok(pairs)
+}
+
+/// Groups pairs into clusters, most similar first. Pairs with an item missing from
+/// `items` are left out.
+fn clusters(
+    pairs: HashMap<(i64, i64), f32>,
+    items: &HashMap<i64, DuplicateItem>,
+) -> Vec<DuplicateCluster> {
+    let pairs: Vec<ScoredPair> = pairs
+        .into_iter()
+        .filter(|((a, b), _)| items.contains_key(a) && items.contains_key(b))
+        .collect();
+    let mut sets = UnionFind {
+        parents: HashMap::new(),
+    };
+    for ((a, b), _) in &pairs {
+        sets.union(*a, *b);
+    }
+
+    let mut by_root: HashMap<i64, Vec<ScoredPair>> = HashMap::new();
+    for (pair, score) in pairs {
+        by_root
+            .entry(sets.find(pair.0))
+            .or_default()
+            .push((pair, score));
+    }
+
+    let mut clusters: Vec<DuplicateCluster> = by_root
+        .into_values()
+        .map(|mut pairs| {
+            pairs.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+            let mut ids: Vec<i64> = pairs.iter().flat_map(|((a, b), _)| [*a, *b]).collect();
+            ids.sort();
+            ids.dedup();
+            let position = |id: &i64| ids.binary_search(id).expect("Id should be in cluster");
+            DuplicateCluster {
+                score: pairs[0].1,
+                pairs: pairs
+                    .iter()
+                    .map(|((a, b), score)| DuplicatePair {
+                        first: position(a),
+                        second: position(b),
+                        score: *score,
+                    })
+                    .collect(),
+                items: ids.iter().map(|id| items[id].clone()).collect(),
+            }
+        })
+        .collect();
+    clusters.sort_by(|a, b| b.score.total_cmp(&a.score));
+    clusters
+}
+
+/// Blocks of different notes scoring at least `threshold` against each other
+pub fn duplicate_blocks(
+    threshold: f32,
+    neighbors: usize,
+    config: &MindmapConfig,
+) -> Result<Vec<DuplicateCluster>> {
+    let index = VectorIndex::view(config)?;
+    let embeddings = database::get_embeddings(config)?;
+    let paths: HashMap<i64, PathBuf> = database::get_ids_by_path(None, config)?
+        .into_iter()
+        .flat_map(|(path, ids)| ids.into_iter().map(move |id| (id, path.clone())))
+        .collect();
+    let pairs = similar_pairs(&embeddings, &index, &paths, neighbors, threshold)?;
+
+    let ids: Vec<i64> = pairs.keys().flat_map(|(a, b)| [*a, *b]).collect();
+    let items = database::get_by_ids(&ids, config)?
+        .into_iter()
+        .map(|(id, sentence)| {
+            let item = DuplicateItem {
+                path: sentence.path,
+                start_line_no: Some(sentence.start_line_no),
+                end_line_no: Some(sentence.end_line_no),
+            };
+            (id, item)
+        })
+        .collect();
+    Ok(clusters(pairs, &items))
+}
+
+/// Notes scoring at least `threshold` against each other, each note being the mean of its
+/// blocks' embeddings
+pub fn duplicate_notes(
+    threshold: f32,
+    neighbors: usize,
+    config: &MindmapConfig,
+) -> Result<Vec<DuplicateCluster>> {
+    let mut notes = vec![];
+    let mut paths = HashMap::new();
+    for (i, (path, mean)) in database::get_note_embeddings(config)?
+        .into_iter()
+        .enumerate()
+    {
+        notes.push((i as i64, mean));
+        paths.insert(i as i64, path);
+    }
+
+    let mut index = VectorIndex::in_memory();
+    for (id, mean) in &notes {
+        index.insert(*id, mean)?;
+    }
+    let pairs = similar_pairs(&notes, &index, &paths, neighbors, threshold)?;
+    let items = paths
+        .into_iter()
+        .map(|(id, path)| {
+            let item = DuplicateItem {
+                path,
+                start_line_no: None,
+                end_line_no: None,
+            };
+            (id, item)
+        })
+        .collect();
+    Ok(clusters(pairs, &items))
+}
+
+fn format_item(item: &DuplicateItem) -> String {
+    match (item.start_line_no, item.end_line_no) {
+        (Some(start), Some(end)) => format!("{}:{}-{}", item.path.display(), start, end),
+        _ => item.path.display().to_string(),
+    }
+}
+
+pub fn format_clusters(clusters: &[DuplicateCluster], format: OutputFormat) -> String {
+    match format {
+        OutputFormat::Json => serde_json::to_string(clusters).unwrap(),
+        // vimgrep style, a blank line between clusters
+        OutputFormat::Raw => clusters
+            .iter()
+            .map(|c| {
+                c.items
+                    .iter()
+                    .map(|i| format!("{}:{}:1\n", i.path.display(), i.start_line_no.unwrap_or(1)))
+                    .collect::<String>()
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+        OutputFormat::List => clusters
+            .iter()
+            .map(|c| {
+                let title = format!("{} items - {:.3}", c.items.len(), c.score);
+                let items: Vec<String> = c.items.iter().map(format_item).collect();
+                format!("{}\n{}", title.blue(), items.join("\n"))
+            })
+            .collect::<Vec<_>>()
+            .join("\n\n"),
+    }
+}
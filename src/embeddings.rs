@@ -110,6 +110,31 @@ impl Display for ModelType {
     }
 }
 
+/// The mean of several embeddings, e.g. to stand for a whole note
+pub fn mean(embeddings: &[&Embedding]) -> Option<Embedding> {
+    let count = embeddings.len() as f32;
+    let mut mean = vec![0.0; embeddings.first()?.len()];
+    for embedding in embeddings {
+        for (sum, value) in mean.iter_mut().zip(embedding.iter()) {
+            *sum += value / count;
+        }
+    }
+    Some(mean)
+}
+
+pub fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+/// Scales an embedding to unit length, so dot products are cosine similarities
+pub fn normalize(mut v: Embedding) -> Embedding {
+    let norm = dot(&v, &v).sqrt();
+    if norm > 0.0 {
+        v.iter_mut().for_each(|x| *x /= norm);
+    }
+    v
+}
+
 pub struct Model {
     model: SentenceEmbeddingsModel,
 }
@@ -130,6 +155,9 @@ impl Model {
     }
 
     pub fn encode_many(&self, sentences: Vec<&str>) -> Result<Vec<Embedding>> {
+        if sentences.is_empty() {
+            return Ok(vec![]);
+        }
         let embeddings = self.model.encode(&sentences)?;
         Ok(embeddings)
     }
@@ -1,92 +1,524 @@
-use itertools::Itertools;
+use clap::ValueEnum;
+use colored::Colorize;
+use indicatif::{ProgressBar, ProgressStyle};
 use markdown::mdast::Node;
-use std::path::Path;
+use rayon::prelude::*;
+use serde::Serialize;
+use std::{
+    any::Any,
+    collections::HashSet,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::mpsc,
+    thread,
+    time::{Instant, UNIX_EPOCH},
+};
 use walkdir::WalkDir;
 
 use crate::{
+    chunking::{self, Chunk},
     config::MindmapConfig,
-    database::{self, EmbeddedSentence},
+    database::{self, EmbeddedSentence, FileRecord, FileStamp},
     embeddings::Model,
+    filter::PathFilter,
+    frontmatter,
+    index::VectorIndex,
+    links::{self, Link},
+    server::{self, FileOp},
 };
 use anyhow::{anyhow, Result};
 use std::fs;
 
-pub fn recompute_all(config: &MindmapConfig) -> Result<()> {
+/// Parsed files waiting for the model before the parser threads block
+const QUEUE_SIZE: usize = 64;
+/// Minimum number of chunks encoded and written together
+const BATCH_SIZE: usize = 128;
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum ReportFormat {
+    /// Human readable summary
+    Text,
+    /// JSON report, including every error
+    Json,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FileError {
+    pub path: PathBuf,
+    pub error: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct IndexReport {
+    pub files_indexed: usize,
+    pub chunks_indexed: usize,
+    pub files_removed: usize,
+    pub files_unchanged: usize,
+    pub elapsed_secs: f64,
+    pub errors: Vec<FileError>,
+}
+
+impl IndexReport {
+    fn add_error(&mut self, path: PathBuf, err: anyhow::Error) {
+        log::warn!("Failed to index {:?}: {:#}", path, err);
+        self.errors.push(FileError {
+            path,
+            error: format!("{:#}", err),
+        });
+    }
+
+    pub fn summary(&self) -> String {
+        format!(
+            "Indexed {} files ({} chunks) in {:.1}s ({:.1} files/sec), {} removed, {} unchanged, {} failed",
+            self.files_indexed,
+            self.chunks_indexed,
+            self.elapsed_secs,
+            self.files_indexed as f64 / self.elapsed_secs.max(f64::EPSILON),
+            self.files_removed,
+            self.files_unchanged,
+            self.errors.len()
+        )
+    }
+
+    pub fn format(&self, format: ReportFormat) -> String {
+        match format {
+            ReportFormat::Json => serde_json::to_string(self).unwrap(),
+            ReportFormat::Text => {
+                let mut lines = vec![self.summary().green().to_string()];
+                for err in &self.errors {
+                    lines.push(format!(
+                        "{}: {}",
+                        err.path.display().to_string().red(),
+                        err.error
+                    ));
+                }
+                lines.join("\n")
+            }
+        }
+    }
+}
+
+/// What an incremental run would do, compared to what is in the database
+#[derive(Debug, Default, Serialize)]
+pub struct IndexPlan {
+    /// The model or chunking changed since the last run, so everything gets reindexed
+    pub settings_changed: bool,
+    pub new: Vec<PathBuf>,
+    pub changed: Vec<PathBuf>,
+    pub deleted: Vec<PathBuf>,
+    pub unchanged: Vec<PathBuf>,
+    pub estimated_chunks: Option<usize>,
+    pub estimated_secs: Option<f64>,
+}
+
+impl IndexPlan {
+    /// The files that need to be embedded
+    pub fn to_index(&self) -> impl Iterator<Item = &PathBuf> {
+        self.new.iter().chain(&self.changed)
+    }
+
+    pub fn is_up_to_date(&self) -> bool {
+        !self.settings_changed
+            && self.new.is_empty()
+            && self.changed.is_empty()
+            && self.deleted.is_empty()
+    }
+
+    /// Chunks the files to index and uses the throughput of recent runs to guess
+    /// how long embedding them will take
+    pub fn estimate(&mut self, config: &MindmapConfig) -> Result<()> {
+        let paths: Vec<&PathBuf> = self.to_index().collect();
+        let chunks = paths
+            .par_iter()
+            .filter_map(|path| chunk_file(path, config).ok())
+            .map(|file| file.chunks.len())
+            .sum();
+        self.estimated_chunks = Some(chunks);
+        self.estimated_secs = database::get_throughput(config)?.map(|rate| chunks as f64 / rate);
+        Ok(())
+    }
+
+    pub fn summary(&self) -> String {
+        let mut summary = format!(
+            "{} new, {} changed, {} deleted, {} unchanged",
+            self.new.len(),
+            self.changed.len(),
+            self.deleted.len(),
+            self.unchanged.len()
+        );
+        if self.settings_changed {
+            summary.push_str(" (model or chunking changed, reindexing everything)");
+        }
+        if let Some(chunks) = self.estimated_chunks {
+            summary.push_str(&format!(", ~{} chunks to embed", chunks));
+        }
+        match self.estimated_secs {
+            Some(secs) => summary.push_str(&format!(" in ~{:.0}s", secs)),
+            None if self.estimated_chunks.is_some() => {
+                summary.push_str(" (no previous runs to estimate the time from)")
+            }
+            None => {}
+        }
+        summary
+    }
+
+    pub fn format(&self, format: ReportFormat) -> String {
+        match format {
+            ReportFormat::Json => serde_json::to_string(self).unwrap(),
+            ReportFormat::Text => {
+                let mut lines = vec![self.summary().green().to_string()];
+                let changes = [
+                    (&self.new, "+".green()),
+                    (&self.changed, "~".yellow()),
+                    (&self.deleted, "-".red()),
+                ];
+                for (paths, marker) in changes {
+                    for path in paths {
+                        lines.push(format!("{} {}", marker, path.display()));
+                    }
+                }
+                lines.join("\n")
+            }
+        }
+    }
+}
+
+pub struct ParsedFile {
+    pub path: PathBuf,
+    pub stamp: FileStamp,
+    /// When the file system says the file was created, if it keeps track
+    pub created: Option<i64>,
+    pub content: String,
+    pub ast: Node,
+}
+
+/// A file split into chunks, ready to be encoded
+struct ChunkedFile {
+    path: PathBuf,
+    stamp: FileStamp,
+    created: Option<i64>,
+    tags: Vec<String>,
+    collection: Option<String>,
+    chunks: Vec<Chunk>,
+    links: Vec<Link>,
+}
+
+/// Lists every file under the data roots that should be indexed
+pub fn collect_files(config: &MindmapConfig) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    let mut seen = HashSet::new();
+    for root in config.get_roots() {
+        let filter = PathFilter::for_root(&root, config)?;
+        let walker = WalkDir::new(&root.path)
+            .follow_links(root.follow_symlinks)
+            .into_iter()
+            .filter_entry(|e| {
+                e.depth() == 0 || !filter.is_ignored(e.path(), e.file_type().is_dir())
+            })
+            .filter_map(|e| e.ok())
+            .filter(|e| !e.file_type().is_dir());
+
+        // Overlapping roots and symlinks can reach the same file more than once
+        for entry in walker {
+            let path = entry.into_path();
+            let real_path = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+            if seen.insert(real_path) {
+                files.push(path);
+            }
+        }
+    }
+    Ok(files)
+}
+
+fn stamp(path: &Path) -> Result<FileStamp> {
+    let metadata = fs::metadata(path)?;
+    let modified = metadata.modified()?.duration_since(UNIX_EPOCH)?;
+    Ok(FileStamp {
+        modified: modified.as_millis() as i64,
+        size: metadata.len() as i64,
+    })
+}
+
+/// Compares the files on disk with the database. Every indexed file counts as changed
+/// when the model or chunking changed since the last run, or with `force`.
+pub fn plan(config: &MindmapConfig, force: bool) -> Result<IndexPlan> {
+    let stamps = database::get_stamps(config)?;
+    let mut indexed: HashSet<PathBuf> = database::get_paths(config)?.into_iter().collect();
+    indexed.extend(stamps.keys().cloned());
+
+    let mut plan = IndexPlan {
+        settings_changed: database::get_fingerprint(config)?
+            .is_some_and(|f| f != config.index_fingerprint()),
+        ..Default::default()
+    };
+    for path in collect_files(config)? {
+        if !indexed.remove(&path) {
+            plan.new.push(path);
+        } else if force || plan.settings_changed || stamps.get(&path) != stamp(&path).ok().as_ref()
+        {
+            plan.changed.push(path);
+        } else {
+            plan.unchanged.push(path);
+        }
+    }
+
+    // Whatever is left is in the database but gone from disk or ignored now
+    plan.deleted = indexed.into_iter().collect();
+    plan.deleted.sort();
+    Ok(plan)
+}
+
+fn progress_bar(len: usize) -> ProgressBar {
+    let style = ProgressStyle::with_template(
+        "{spinner:.blue} [{elapsed_precise}] {wide_bar:.cyan/blue} {pos}/{len} files ({per_sec}, ETA {eta})",
+    )
+    .expect("Progress bar template should be valid")
+    .progress_chars("=> ");
+    let progress = ProgressBar::new(len as u64);
+    progress.set_style(style);
+    progress
+}
+
+/// Brings the database in line with a plan: removes deleted files and embeds new
+/// and changed ones
+pub fn recompute_all(config: &MindmapConfig, plan: &IndexPlan) -> Result<IndexReport> {
+    let mut report = IndexReport {
+        files_unchanged: plan.unchanged.len(),
+        ..Default::default()
+    };
+
+    if plan.settings_changed {
+        // Old and new embeddings can't share an index, e.g. after a model swap
+        database::delete_all(config)?;
+    }
+    let mut index = VectorIndex::open(config)?;
+    if !plan.deleted.is_empty() {
+        database::delete_files(&plan.deleted, &mut index, config)?;
+        report.files_removed = plan.deleted.len();
+    }
+
+    let paths: Vec<&PathBuf> = plan.to_index().collect();
+    if paths.is_empty() {
+        save_and_notify(&index, config)?;
+        return Ok(report);
+    }
+
     let model = Model::new(config)?;
-    let walker = WalkDir::new(&config.data_dir)
-        .into_iter()
-        .filter_map(|e| e.ok());
+    let progress = progress_bar(paths.len());
+    let started = Instant::now();
+
+    // Parse and chunk files in parallel while the model encodes on this thread
+    let (tx, rx) = mpsc::sync_channel(QUEUE_SIZE);
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            paths.par_iter().for_each_with(tx, |tx, path| {
+                tx.send((path.to_path_buf(), chunk_file(path, config))).ok();
+            });
+        });
 
-    for entry in walker {
-        if entry.file_type().is_dir() {
-            continue;
+        let mut batch = vec![];
+        let mut batch_chunks = 0;
+        for (path, file) in rx {
+            match file {
+                Ok(file) => {
+                    batch_chunks += file.chunks.len();
+                    batch.push(file);
+                }
+                Err(err) => {
+                    report.add_error(path, err);
+                    progress.inc(1);
+                }
+            }
+            if batch_chunks >= BATCH_SIZE {
+                let len = batch.len() as u64;
+                store_or_isolate(&batch, config, &model, &mut index, &mut report);
+                progress.inc(len);
+                batch.clear();
+                batch_chunks = 0;
+            }
         }
-        let path = entry.into_path();
-        if path.extension().unwrap_or_default() != "md" {
-            continue;
+        if !batch.is_empty() {
+            store_or_isolate(&batch, config, &model, &mut index, &mut report);
+            progress.inc(batch.len() as u64);
         }
-        println!("Processing {:?}", path);
-        let ast = parse_file(&path)?;
-        process_and_store_file(&path, config, &ast, &model)?;
+    });
+    progress.finish_and_clear();
+    save_and_notify(&index, config)?;
+
+    report.elapsed_secs = started.elapsed().as_secs_f64();
+    database::insert_run(report.chunks_indexed, report.elapsed_secs, config)?;
+    log::info!("{}", report.summary());
+    Ok(report)
+}
+
+/// Reindexes one file into an open index and saves it
+pub fn recompute_file(file: &Path, index: &mut VectorIndex, config: &MindmapConfig) -> Result<()> {
+    let filter = PathFilter::for_root(&config.get_root(file), config)?;
+    if filter.is_ignored(file, false) {
+        println!("{}: {:?}", "Skipping ignored file".yellow(), file);
+        return Ok(());
     }
+
+    let model = Model::new(config)?;
+    let chunked = chunk_file(file, config)?;
+    store_batch(&[chunked], config, &model, index)?;
+    index.save()?;
+    server::notify_change(file, FileOp::Update, config).ok();
     Ok(())
 }
 
-pub fn recompute_file(file: &Path, config: &MindmapConfig) -> Result<()> {
-    let model = Model::new(config)?;
-    let ast = parse_file(file)?;
-    process_and_store_file(file, config, &ast, &model)?;
+/// Persists the index and lets the server know it should reload it
+fn save_and_notify(index: &VectorIndex, config: &MindmapConfig) -> Result<()> {
+    index.save()?;
+    server::notify_rebuild(config).ok();
     Ok(())
 }
 
-pub fn process_and_store_file(
-    file: &Path,
+/// Stores a batch, falling back to one file at a time to pin down the ones that fail
+fn store_or_isolate(
+    files: &[ChunkedFile],
     config: &MindmapConfig,
-    ast: &Node,
     model: &Model,
-) -> Result<()> {
-    // Delete existing data
-    database::delete_file(file, config)?;
+    index: &mut VectorIndex,
+    report: &mut IndexReport,
+) {
+    if let Ok(num_chunks) = store_batch(files, config, model, index) {
+        report.files_indexed += files.len();
+        report.chunks_indexed += num_chunks;
+        return;
+    }
 
-    // Process 10 blocks at a time
-    let iter = compute_file(ast, file, model);
-    for chunk in iter.chunks(10).into_iter() {
-        let embs: Vec<EmbeddedSentence> = chunk.collect();
-        database::insert_many(&embs, config)?;
+    for file in files {
+        match store_batch(std::slice::from_ref(file), config, model, index) {
+            Ok(num_chunks) => {
+                report.files_indexed += 1;
+                report.chunks_indexed += num_chunks;
+            }
+            Err(err) => report.add_error(file.path.clone(), err),
+        }
     }
+}
 
-    Ok(())
+/// Encodes a batch of files in one go and replaces their rows in the database
+fn store_batch(
+    files: &[ChunkedFile],
+    config: &MindmapConfig,
+    model: &Model,
+    index: &mut VectorIndex,
+) -> Result<usize> {
+    let texts: Vec<String> = files
+        .iter()
+        .flat_map(|file| &file.chunks)
+        .map(
+            |chunk| match (&chunk.breadcrumb, config.chunking.breadcrumbs) {
+                (Some(breadcrumb), true) => format!("{}\n{}", breadcrumb, chunk.text),
+                _ => chunk.text.clone(),
+            },
+        )
+        .collect();
+    let embeddings = model.encode_many(texts.iter().map(String::as_str).collect())?;
+
+    let records: Vec<FileRecord> = files
+        .iter()
+        .map(|file| FileRecord {
+            path: file.path.clone(),
+            stamp: file.stamp,
+            created: file.created,
+            tags: file.tags.clone(),
+        })
+        .collect();
+    let embs: Vec<EmbeddedSentence> = files
+        .iter()
+        .flat_map(|file| file.chunks.iter().map(move |chunk| (file, chunk)))
+        .zip(embeddings)
+        .map(|((file, chunk), embedding)| EmbeddedSentence {
+            path: file.path.clone(),
+            collection: file.collection.clone(),
+            start_line_no: chunk.start_line_no,
+            start_col: chunk.start_col,
+            start_offset: chunk.start_offset,
+            end_line_no: chunk.end_line_no,
+            end_col: chunk.end_col,
+            end_offset: chunk.end_offset,
+            breadcrumb: chunk.breadcrumb.clone(),
+            lang: chunk.lang.clone(),
+            embedding,
+        })
+        .collect();
+
+    let links: Vec<Link> = files.iter().flat_map(|f| f.links.clone()).collect();
+
+    database::replace_files(&records, &embs, &links, index, config)?;
+    Ok(embs.len())
 }
 
-fn parse_file(path: &Path) -> Result<Node> {
+fn parse_file(path: &Path) -> Result<ParsedFile> {
+    // Stamped before reading so an edit made meanwhile shows up as a change next time
+    let stamp = stamp(path)?;
+    let created = fs::metadata(path)?
+        .created()
+        .ok()
+        .and_then(|created| created.duration_since(UNIX_EPOCH).ok())
+        .map(|created| created.as_millis() as i64);
     let content = fs::read_to_string(path)?;
-    let opts = markdown::ParseOptions::default();
+    let mut opts = markdown::ParseOptions::gfm();
+    opts.constructs.frontmatter = true;
     let ast = markdown::to_mdast(&content, &opts).map_err(|e| anyhow!(e))?;
-    Ok(ast)
-}
-
-fn compute_file<'a>(
-    ast: &'a Node,
-    path: &'a Path,
-    model: &'a Model,
-) -> impl Iterator<Item = EmbeddedSentence> + 'a {
-    ast.children().unwrap().iter().map(|child| {
-        let pos = child.position().expect("No position");
-        let start = &pos.start.line;
-        let end = &pos.end.line;
-        let content = child.to_string();
-        let emb = model.encode(&content).unwrap();
-
-        EmbeddedSentence {
-            path: path.to_path_buf(),
-            start_line_no: *start,
-            end_line_no: *end,
-            embedding: emb,
-        }
+    Ok(ParsedFile {
+        path: path.to_path_buf(),
+        stamp,
+        created,
+        content,
+        ast,
+    })
+}
+
+fn chunk_parsed(parsed: &ParsedFile, config: &MindmapConfig) -> ChunkedFile {
+    let strategy = config.get_chunking_strategy(&parsed.path);
+    let frontmatter = frontmatter::parse(&parsed.ast);
+    ChunkedFile {
+        path: parsed.path.clone(),
+        stamp: parsed.stamp,
+        created: frontmatter.created.or(parsed.created),
+        tags: frontmatter.tags,
+        collection: config.get_root(&parsed.path).collection,
+        links: links::extract(&parsed.path, &parsed.ast),
+        chunks: chunking::chunk(
+            &parsed.ast,
+            &parsed.content,
+            strategy,
+            &config.chunking.nested,
+            &config.chunking.code_blocks,
+        ),
+    }
+}
+
+fn chunk_file(path: &Path, config: &MindmapConfig) -> Result<ChunkedFile> {
+    // A parser bug on one odd file must not take down the whole run
+    panic::catch_unwind(AssertUnwindSafe(|| {
+        let parsed = parse_file(path)?;
+        Ok(chunk_parsed(&parsed, config))
+    }))
+    .unwrap_or_else(|payload| {
+        Err(anyhow!(
+            "Panicked while parsing: {}",
+            panic_message(&*payload)
+        ))
     })
 }
 
-pub fn delete_file(file: &Path, config: &MindmapConfig) -> Result<()> {
-    database::delete_file(file, config)?;
+/// The message a panic was raised with, when it has one
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "no message".to_string())
+}
+
+pub fn delete_file(file: &Path, index: &mut VectorIndex, config: &MindmapConfig) -> Result<()> {
+    database::delete_files(&[file.to_path_buf()], index, config)?;
+    index.save()?;
+    server::notify_change(file, FileOp::Delete, config).ok();
     Ok(())
 }
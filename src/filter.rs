@@ -0,0 +1,118 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::Result;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::gitignore::Gitignore;
+
+use crate::config::{IgnoreConfig, MindmapConfig, RootConfig};
+
+/// Ignore files honored in every directory, the first one takes precedence
+const IGNORE_FILES: [&str; 2] = [".mindmapignore", ".gitignore"];
+
+/// Decides which paths under a root get indexed. Shared by the walker, the watcher
+/// and `recompute-file` so they all agree on what is part of the MindMap.
+pub struct PathFilter {
+    root: PathBuf,
+    ignore_files: bool,
+    include: GlobSet,
+    exclude: GlobSet,
+    cache: Mutex<HashMap<PathBuf, Vec<Gitignore>>>,
+}
+
+fn build_globset(globs: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(Glob::new(glob)?);
+    }
+    Ok(builder.build()?)
+}
+
+impl PathFilter {
+    pub fn new(root: &Path, config: &IgnoreConfig) -> Result<Self> {
+        Ok(Self {
+            root: root.to_path_buf(),
+            ignore_files: config.ignore_files,
+            include: build_globset(&config.include)?,
+            exclude: build_globset(&config.exclude)?,
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn for_root(root: &RootConfig, config: &MindmapConfig) -> Result<Self> {
+        Self::new(&root.path, config.get_ignore(root))
+    }
+
+    pub fn is_ignore_file(path: &Path) -> bool {
+        path.file_name()
+            .is_some_and(|name| IGNORE_FILES.iter().any(|f| name == *f))
+    }
+
+    /// Forgets the parsed ignore files, e.g. after one of them changed
+    pub fn clear_cache(&self) {
+        self.cache.lock().unwrap().clear();
+    }
+
+    fn ignore_files_in(&self, dir: &Path) -> Vec<Gitignore> {
+        let mut cache = self.cache.lock().unwrap();
+        cache
+            .entry(dir.to_path_buf())
+            .or_insert_with(|| {
+                IGNORE_FILES
+                    .iter()
+                    .map(|name| dir.join(name))
+                    .filter(|path| path.is_file())
+                    .filter_map(|path| {
+                        let (gitignore, err) = Gitignore::new(&path);
+                        if let Some(err) = err {
+                            log::warn!("Failed to parse {:?}: {}", path, err);
+                        }
+                        (!gitignore.is_empty()).then_some(gitignore)
+                    })
+                    .collect()
+            })
+            .clone()
+    }
+
+    fn is_ignored_by_files(&self, path: &Path, is_dir: bool) -> bool {
+        // Deeper ignore files override the ones closer to the root, like git does
+        let dirs = path
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| dir.starts_with(&self.root));
+        for dir in dirs {
+            for gitignore in self.ignore_files_in(dir) {
+                let matched = gitignore.matched_path_or_any_parents(path, is_dir);
+                if matched.is_ignore() {
+                    return true;
+                }
+                if matched.is_whitelist() {
+                    return false;
+                }
+            }
+        }
+        false
+    }
+
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        let relative = path.strip_prefix(&self.root).unwrap_or(path);
+
+        // A path is excluded when it or any of its parent directories matches
+        let excluded = relative
+            .ancestors()
+            .filter(|p| !p.as_os_str().is_empty())
+            .any(|p| self.exclude.is_match(p));
+        if excluded {
+            return true;
+        }
+
+        if !is_dir && !self.include.is_empty() && !self.include.is_match(relative) {
+            return true;
+        }
+
+        self.ignore_files && path.starts_with(&self.root) && self.is_ignored_by_files(path, is_dir)
+    }
+}
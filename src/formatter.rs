@@ -1,4 +1,7 @@
-use crate::search::SearchResult;
+use crate::{
+    grouping::{self, Grouping},
+    search::{PartScore, SearchResult},
+};
 use clap::ValueEnum;
 use colored::Colorize;
 use serde::Serialize;
@@ -40,17 +43,30 @@ impl Display for OutputFormat {
 #[derive(Serialize, Debug)]
 struct SearchResultWithContext {
     pub path: PathBuf,
+    pub collection: Option<String>,
     pub start_line_no: usize,
+    pub start_col: usize,
+    pub start_offset: usize,
     pub end_line_no: usize,
-    pub distance: f32,
+    pub end_col: usize,
+    pub end_offset: usize,
+    pub breadcrumb: Option<String>,
+    pub lang: Option<String>,
+    pub score: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rerank_score: Option<f32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub parts: Vec<PartScore>,
     context: String,
 }
 
-fn get_context(result: &SearchResult) -> String {
+/// The lines of the result, read again from the file since it may have changed or gone
+/// since it was indexed
+fn get_context(result: &SearchResult) -> anyhow::Result<String> {
     let start_no = result.start_line_no;
     let end_no = result.end_line_no;
 
-    let content = std::fs::read_to_string(&result.path).unwrap();
+    let content = std::fs::read_to_string(&result.path)?;
     let lines: Vec<&str> = content.lines().collect();
     let total_lines = lines.len();
     let start_no = if start_no > 0 { start_no - 1 } else { 0 };
@@ -59,21 +75,47 @@ fn get_context(result: &SearchResult) -> String {
     } else {
         total_lines
     };
-    lines[start_no..end_no].join("\n")
+    Ok(lines.get(start_no..end_no).unwrap_or_default().join("\n"))
 }
 
-pub fn format(results: &[SearchResult], format: OutputFormat) -> String {
-    let with_context = results
-        .iter()
-        .map(|r| SearchResultWithContext {
-            path: r.path.clone(),
-            start_line_no: r.start_line_no,
-            end_line_no: r.end_line_no,
-            distance: r.distance,
-            context: get_context(r),
-        })
-        .collect();
+/// A note with its best passages
+#[derive(Serialize, Debug)]
+struct FileResultsWithContext {
+    pub path: PathBuf,
+    pub score: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rerank_score: Option<f32>,
+    passages: Vec<SearchResultWithContext>,
+}
+
+fn with_context(r: &SearchResult) -> SearchResultWithContext {
+    SearchResultWithContext {
+        path: r.path.clone(),
+        collection: r.collection.clone(),
+        start_line_no: r.start_line_no,
+        start_col: r.start_col,
+        start_offset: r.start_offset,
+        end_line_no: r.end_line_no,
+        end_col: r.end_col,
+        end_offset: r.end_offset,
+        breadcrumb: r.breadcrumb.clone(),
+        lang: r.lang.clone(),
+        score: r.score,
+        rerank_score: r.rerank_score,
+        parts: r.parts.clone(),
+        context: get_context(r).unwrap_or_else(|err| {
+            log::warn!("No context for {:?}: {}", r.path, err);
+            String::new()
+        }),
+    }
+}
+
+pub fn format(results: &[SearchResult], grouping: Grouping, format: OutputFormat) -> String {
+    if grouping == Grouping::Files {
+        return format_files(results, format);
+    }
 
+    let with_context = results.iter().map(with_context).collect();
     match format {
         OutputFormat::List => list(&with_context),
         OutputFormat::Raw => raw(&with_context),
@@ -81,22 +123,65 @@ pub fn format(results: &[SearchResult], format: OutputFormat) -> String {
     }
 }
 
+/// Shows results one note at a time, each with its passages
+fn format_files(results: &[SearchResult], format: OutputFormat) -> String {
+    let files: Vec<FileResultsWithContext> = grouping::by_file(results)
+        .into_iter()
+        .map(|f| FileResultsWithContext {
+            path: f.path.to_path_buf(),
+            score: f.score,
+            rerank_score: f.rerank_score,
+            passages: f.passages.into_iter().map(with_context).collect(),
+        })
+        .collect();
+
+    match format {
+        OutputFormat::List => files
+            .iter()
+            .map(|f| {
+                let title = format!(
+                    "{} - {}",
+                    f.path.display(),
+                    format_score(f.score, f.rerank_score)
+                );
+                format!("{}\n{}", title.blue().bold(), list(&f.passages))
+            })
+            .collect::<Vec<_>>()
+            .join("\n\n"),
+        OutputFormat::Raw => files.iter().map(|f| raw(&f.passages)).collect(),
+        OutputFormat::Json => serde_json::to_string(&files).unwrap(),
+    }
+}
+
+fn format_score(score: f32, rerank_score: Option<f32>) -> String {
+    match rerank_score {
+        Some(rerank_score) => format!("{:.3} (reranked {:.3})", score, rerank_score),
+        None => format!("{:.3}", score),
+    }
+}
+
 fn list(results: &Vec<SearchResultWithContext>) -> String {
     let mut sentences = vec![];
     for r in results {
         let title = format!(
-            "{}:{}:{} - {}",
+            "{}:{}:{}-{}:{} - {}",
             r.path.display(),
             r.start_line_no,
+            r.start_col,
             r.end_line_no,
-            r.distance
+            r.end_col,
+            format_score(r.score, r.rerank_score)
         );
-        let sentence = format!("{}\n{}", title.blue(), r.context);
+        let sentence = match &r.breadcrumb {
+            Some(breadcrumb) => format!("{}\n{}\n{}", title.blue(), breadcrumb.dimmed(), r.context),
+            None => format!("{}\n{}", title.blue(), r.context),
+        };
         sentences.push(sentence);
     }
     sentences.join("\n\n")
 }
 
+/// vimgrep style `path:line:column`, pointing at the start of each result
 fn raw(results: &Vec<SearchResultWithContext>) -> String {
     let mut fmt = String::new();
     for r in results {
@@ -104,7 +189,7 @@ fn raw(results: &Vec<SearchResultWithContext>) -> String {
             "{}:{}:{}\n",
             r.path.display(),
             r.start_line_no,
-            r.end_line_no
+            r.start_col
         ));
     }
     fmt
@@ -0,0 +1,52 @@
+use chrono::NaiveDate;
+use markdown::mdast::Node;
+use serde_yaml::Value;
+
+/// The metadata MindMap reads from a note's YAML frontmatter
+#[derive(Debug, Default)]
+pub struct Frontmatter {
+    pub tags: Vec<String>,
+    /// Milliseconds since the Unix epoch, from `created` or `date`
+    pub created: Option<i64>,
+}
+
+/// Parses `YYYY-MM-DD`, ignoring whatever time follows it, as midnight UTC
+pub fn parse_date(date: &str) -> Option<i64> {
+    let date = NaiveDate::parse_from_str(date.get(..10)?, "%Y-%m-%d").ok()?;
+    Some(date.and_hms_opt(0, 0, 0)?.and_utc().timestamp_millis())
+}
+
+/// Accepts `tags: [a, b]`, a YAML list or `tags: a, b`, with or without `#`
+fn tags(value: &Value) -> Vec<String> {
+    let tags: Vec<&str> = match value {
+        Value::Sequence(seq) => seq.iter().filter_map(Value::as_str).collect(),
+        Value::String(s) => s.split([',', ' ']).collect(),
+        _ => vec![],
+    };
+    tags.into_iter()
+        .map(|tag| tag.trim().trim_start_matches('#').to_lowercase())
+        .filter(|tag| !tag.is_empty())
+        .collect()
+}
+
+pub fn parse(ast: &Node) -> Frontmatter {
+    let yaml = ast
+        .children()
+        .into_iter()
+        .flatten()
+        .find_map(|node| match node {
+            Node::Yaml(yaml) => Some(&yaml.value),
+            _ => None,
+        });
+    let Some(Ok(Value::Mapping(map))) = yaml.map(|y| serde_yaml::from_str::<Value>(y)) else {
+        return Frontmatter::default();
+    };
+
+    let field = |names: &[&str]| names.iter().find_map(|name| map.get(*name));
+    Frontmatter {
+        tags: field(&["tags", "tag"]).map(tags).unwrap_or_default(),
+        created: field(&["created", "date"])
+            .and_then(Value::as_str)
+            .and_then(parse_date),
+    }
+}
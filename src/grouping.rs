@@ -0,0 +1,165 @@
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter},
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use clap::ValueEnum;
+
+use crate::search::SearchResult;
+
+/// How many passages a note shows in `files` mode by default
+pub const DEFAULT_PASSAGES: usize = 3;
+
+/// How results from the same file are put together
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Grouping {
+    /// Every block on its own
+    #[default]
+    None,
+    /// Adjacent or overlapping blocks of a file merged into one result
+    Merge,
+    /// The best notes, each with its best passages
+    Files,
+}
+
+impl FromStr for Grouping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Grouping::None),
+            "merge" => Ok(Grouping::Merge),
+            "files" => Ok(Grouping::Files),
+            _ => Err("Invalid grouping".to_string()),
+        }
+    }
+}
+
+impl Display for Grouping {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Grouping::None => write!(f, "none"),
+            Grouping::Merge => write!(f, "merge"),
+            Grouping::Files => write!(f, "files"),
+        }
+    }
+}
+
+/// A note and its best passages, best first
+#[derive(Debug)]
+pub struct FileResults<'a> {
+    pub path: &'a Path,
+    /// The score of its best passage
+    pub score: f32,
+    pub rerank_score: Option<f32>,
+    pub passages: Vec<&'a SearchResult>,
+}
+
+/// Whether nothing but whitespace separates two blocks, e.g. consecutive paragraphs
+fn is_adjacent(first: &SearchResult, second: &SearchResult, content: Option<&str>) -> bool {
+    second.start_offset <= first.end_offset
+        || content
+            .and_then(|c| c.get(first.end_offset..second.start_offset))
+            .is_some_and(|gap| gap.trim().is_empty())
+}
+
+/// Widens `merged` to also cover `other`, keeping the best score
+fn extend(merged: &mut SearchResult, other: SearchResult) {
+    if other.end_offset > merged.end_offset {
+        merged.end_line_no = other.end_line_no;
+        merged.end_col = other.end_col;
+        merged.end_offset = other.end_offset;
+    }
+    if other.lang != merged.lang {
+        merged.lang = None;
+    }
+    if other.relevance() > merged.relevance() {
+        merged.breadcrumb = other.breadcrumb;
+        merged.parts = other.parts;
+    }
+    merged.score = merged.score.max(other.score);
+    merged.rerank_score = match (merged.rerank_score, other.rerank_score) {
+        (Some(a), Some(b)) => Some(a.max(b)),
+        (a, b) => a.or(b),
+    };
+}
+
+/// Merges the adjacent or overlapping blocks of every file into one result, ranked where
+/// its best block was
+pub fn merge_adjacent(results: Vec<SearchResult>) -> Vec<SearchResult> {
+    let mut by_path: HashMap<PathBuf, Vec<(usize, SearchResult)>> = HashMap::new();
+    for (rank, result) in results.into_iter().enumerate() {
+        by_path
+            .entry(result.path.clone())
+            .or_default()
+            .push((rank, result));
+    }
+
+    let mut merged = vec![];
+    for (path, mut blocks) in by_path {
+        blocks.sort_by_key(|(_, b)| (b.start_offset, std::cmp::Reverse(b.end_offset)));
+        let content = fs::read_to_string(&path).ok();
+        let mut blocks = blocks.into_iter();
+        let Some((mut rank, mut current)) = blocks.next() else {
+            continue;
+        };
+        for (block_rank, block) in blocks {
+            if is_adjacent(&current, &block, content.as_deref()) {
+                rank = rank.min(block_rank);
+                extend(&mut current, block);
+            } else {
+                merged.push((rank, std::mem::replace(&mut current, block)));
+                rank = block_rank;
+            }
+        }
+        merged.push((rank, current));
+    }
+
+    merged.sort_by_key(|(rank, _)| *rank);
+    merged.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Keeps the passages of the best `num_files` notes, at most `passages` each
+pub fn top_files(
+    results: Vec<SearchResult>,
+    num_files: usize,
+    passages: usize,
+) -> Vec<SearchResult> {
+    let mut counts: HashMap<PathBuf, usize> = HashMap::new();
+    results
+        .into_iter()
+        .filter(|r| {
+            // A file not seen before only gets in while there's room left
+            if !counts.contains_key(&r.path) && counts.len() >= num_files {
+                return false;
+            }
+            let count = counts.entry(r.path.clone()).or_insert(0);
+            *count += 1;
+            *count <= passages
+        })
+        .collect()
+}
+
+/// Groups ranked results by their file, in the order of each file's best result
+pub fn by_file(results: &[SearchResult]) -> Vec<FileResults<'_>> {
+    let mut files: Vec<FileResults> = vec![];
+    let mut positions: HashMap<&Path, usize> = HashMap::new();
+    for result in results {
+        match positions.get(result.path.as_path()) {
+            Some(&i) => files[i].passages.push(result),
+            None => {
+                positions.insert(&result.path, files.len());
+                files.push(FileResults {
+                    path: &result.path,
+                    score: result.score,
+                    rerank_score: result.rerank_score,
+                    passages: vec![result],
+                });
+            }
+        }
+    }
+    files
+}
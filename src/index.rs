@@ -0,0 +1,250 @@
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use usearch::{Index, IndexOptions, MetricKind, ScalarKind};
+
+use crate::{config::MindmapConfig, database, utils};
+
+/// Room made for new embeddings whenever the index fills up, at least
+const MIN_RESERVE: usize = 1024;
+
+/// Where the sentences stood when an index was last brought in line with them. It
+/// is saved along with the index so a stale one gets rebuilt.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexState {
+    /// The highest rowid of a sentence
+    pub max_id: i64,
+    /// Bumped by every change to the sentences
+    pub generation: i64,
+}
+
+/// An HNSW index over every embedding in the database, keyed by the rowid of its
+/// sentence. It is saved next to the database so queries don't rebuild it.
+pub struct VectorIndex {
+    /// `None` until the first embedding tells the number of dimensions
+    index: Option<Index>,
+    path: PathBuf,
+    /// The state of the database the index matches, `None` when it isn't known
+    state: Option<IndexState>,
+}
+
+fn new_index(dimensions: usize) -> Result<Index> {
+    let options = IndexOptions {
+        dimensions,
+        metric: MetricKind::Cos,
+        quantization: ScalarKind::F32,
+        ..Default::default()
+    };
+    Ok(Index::new(&options)?)
+}
+
+/// An index to load a saved one into, which brings its own options
+fn saved_index(path: &Path) -> Result<(Index, &str)> {
+    let path = path
+        .to_str()
+        .ok_or(anyhow!("Invalid index path: {:?}", path))?;
+    Ok((Index::new(&IndexOptions::default())?, path))
+}
+
+fn state_path(path: &Path) -> PathBuf {
+    path.with_extension("usearch.state")
+}
+
+fn saved_state(path: &Path) -> Option<IndexState> {
+    let state = fs::read_to_string(state_path(path)).ok()?;
+    serde_json::from_str(&state).ok()
+}
+
+fn neighbors(keys: Vec<u64>, distances: Vec<f32>) -> Vec<(i64, f32)> {
+    keys.into_iter()
+        .map(|key| key as i64)
+        .zip(distances)
+        .collect()
+}
+
+/// Whether the saved index matches the database. Without sentences there may be no
+/// index file at all, only the state.
+fn is_current(path: &Path, state: IndexState) -> bool {
+    saved_state(path) == Some(state) && (path.exists() || state.max_id == 0)
+}
+
+/// Keeps other processes from loading or saving the index meanwhile
+fn lock(path: &Path) -> Result<fs::File> {
+    utils::lock_file(&path.with_extension("usearch.lock"))
+}
+
+impl VectorIndex {
+    pub fn get_path(config: &MindmapConfig) -> PathBuf {
+        config.db_path.with_extension("usearch")
+    }
+
+    /// Loads the index into memory so it can be updated. It gets rebuilt from the
+    /// database when it is missing or out of sync, e.g. after a crash.
+    pub fn open(config: &MindmapConfig) -> Result<Self> {
+        let path = Self::get_path(config);
+        let _lock = lock(&path)?;
+        let state = database::get_index_state(config)?;
+        if is_current(&path, state) {
+            let mut index = Self {
+                index: None,
+                path,
+                state: Some(state),
+            };
+            if index.path.exists() {
+                let (loaded, path_str) = saved_index(&index.path)?;
+                loaded.load(path_str)?;
+                index.index = Some(loaded);
+            }
+            return Ok(index);
+        }
+        if path.exists() {
+            log::warn!("Index at {:?} is out of date, rebuilding it", path);
+        }
+
+        let mut index = Self {
+            index: None,
+            path,
+            state: Some(state),
+        };
+        for (id, embedding) in database::get_embeddings(config)? {
+            index.insert(id, &embedding)?;
+        }
+        index.write()?;
+        Ok(index)
+    }
+
+    /// An empty index that is never saved, for embeddings not in the database
+    pub fn in_memory() -> Self {
+        Self {
+            index: None,
+            path: PathBuf::new(),
+            state: None,
+        }
+    }
+
+    /// Memory-maps the saved index read-only, so a query doesn't read all of it
+    pub fn view(config: &MindmapConfig) -> Result<Self> {
+        let path = Self::get_path(config);
+        {
+            let _lock = lock(&path)?;
+            let state = database::get_index_state(config)?;
+            if is_current(&path, state) {
+                let mut index = Self {
+                    index: None,
+                    path,
+                    state: Some(state),
+                };
+                if index.path.exists() {
+                    let (viewed, path_str) = saved_index(&index.path)?;
+                    viewed.view(path_str)?;
+                    index.index = Some(viewed);
+                }
+                return Ok(index);
+            }
+        }
+        Self::open(config)
+    }
+
+    /// Reloads the index when another process changed the database since it was opened
+    pub fn sync(&mut self, config: &MindmapConfig) -> Result<()> {
+        if self.state != Some(database::get_index_state(config)?) {
+            *self = Self::open(config)?;
+        }
+        Ok(())
+    }
+
+    /// Records a change to the database the index was just updated with. If the
+    /// index didn't match the database before it, the state is unknown from now on.
+    pub fn advance(&mut self, before: IndexState, after: IndexState) {
+        self.state = (self.state == Some(before)).then_some(after);
+    }
+
+    pub fn insert(&mut self, id: i64, embedding: &[f32]) -> Result<()> {
+        if self.index.is_none() {
+            self.index = Some(new_index(embedding.len())?);
+        }
+        let index = self.index.as_ref().expect("Index should exist");
+        if index.size() >= index.capacity() {
+            index.reserve((index.capacity() * 2).max(MIN_RESERVE))?;
+        }
+        index.add(id as u64, embedding)?;
+        Ok(())
+    }
+
+    pub fn remove(&mut self, id: i64) -> Result<()> {
+        if let Some(index) = &self.index {
+            index.remove(id as u64)?;
+        }
+        Ok(())
+    }
+
+    /// The ids of the `k` nearest embeddings with their cosine distance, only
+    /// considering the `allowed` ids if given
+    pub fn search(
+        &self,
+        embedding: &[f32],
+        k: usize,
+        allowed: Option<&HashSet<i64>>,
+    ) -> Result<Vec<(i64, f32)>> {
+        if let Some(allowed) = allowed {
+            return self.search_where(embedding, k, |id| allowed.contains(&id));
+        }
+        let Some(index) = &self.index else {
+            return Ok(vec![]);
+        };
+        let matches = index.search(embedding, k)?;
+        Ok(neighbors(matches.keys, matches.distances))
+    }
+
+    /// The ids of the `k` nearest embeddings whose id passes `filter`, with their
+    /// cosine distance
+    pub fn search_where(
+        &self,
+        embedding: &[f32],
+        k: usize,
+        filter: impl Fn(i64) -> bool,
+    ) -> Result<Vec<(i64, f32)>> {
+        let Some(index) = &self.index else {
+            return Ok(vec![]);
+        };
+        let matches = index.filtered_search(embedding, k, |key| filter(key as i64))?;
+        Ok(neighbors(matches.keys, matches.distances))
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let _lock = lock(&self.path)?;
+        self.write()
+    }
+
+    /// Writes to a temporary file first so readers never map a half written index.
+    /// The state goes last, so an index cut off halfway gets rebuilt.
+    fn write(&self) -> Result<()> {
+        let state_path = state_path(&self.path);
+        if state_path.exists() {
+            fs::remove_file(&state_path)?;
+        }
+        match &self.index {
+            Some(index) => {
+                let tmp = self.path.with_extension("usearch.tmp");
+                let tmp_str = tmp
+                    .to_str()
+                    .ok_or(anyhow!("Invalid index path: {:?}", tmp))?;
+                index.save(tmp_str)?;
+                fs::rename(&tmp, &self.path)?;
+            }
+            // Nothing indexed, don't leave an older index behind
+            None if self.path.exists() => fs::remove_file(&self.path)?,
+            None => {}
+        }
+        // Saved for an empty index too, so it isn't rebuilt on every query
+        if let Some(state) = self.state {
+            fs::write(&state_path, serde_json::to_string(&state)?)?;
+        }
+        Ok(())
+    }
+}
@@ -1,10 +1,22 @@
+pub mod chunking;
 pub mod config;
 pub mod database;
+pub mod duplicates;
 pub mod embeddings;
 pub mod files;
+pub mod filter;
 pub mod formatter;
+pub mod frontmatter;
+pub mod grouping;
+pub mod index;
+pub mod links;
+pub mod map;
+pub mod query;
+pub mod rerank;
 pub mod search;
 pub mod server;
 pub mod setup;
+pub mod similar;
+pub mod topics;
 pub mod utils;
 pub mod watcher;
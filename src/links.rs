@@ -0,0 +1,273 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{Display, Formatter},
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::Result;
+use colored::Colorize;
+use markdown::mdast::Node;
+use serde::Serialize;
+
+use crate::{config::MindmapConfig, database, formatter::OutputFormat};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub enum LinkKind {
+    /// `[[note]]` or `[[note|alias]]`
+    Wiki,
+    /// `![[note]]`
+    Embed,
+    /// `[text](relative/path.md)`
+    Markdown,
+}
+
+impl FromStr for LinkKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wiki" => Ok(LinkKind::Wiki),
+            "embed" => Ok(LinkKind::Embed),
+            "markdown" => Ok(LinkKind::Markdown),
+            _ => Err("Invalid link kind".to_string()),
+        }
+    }
+}
+
+impl Display for LinkKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LinkKind::Wiki => write!(f, "wiki"),
+            LinkKind::Embed => write!(f, "embed"),
+            LinkKind::Markdown => write!(f, "markdown"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Link {
+    pub source: PathBuf,
+    /// The note name for wiki links, the absolute path for Markdown links
+    pub target: String,
+    pub kind: LinkKind,
+    pub line_no: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct GraphEdge {
+    source: PathBuf,
+    target: Option<PathBuf>,
+    raw_target: String,
+    kind: LinkKind,
+    line_no: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct Graph {
+    nodes: Vec<PathBuf>,
+    edges: Vec<GraphEdge>,
+}
+
+/// Lexically resolves `..` and `.` so links can be compared with indexed paths
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            c => normalized.push(c),
+        }
+    }
+    normalized
+}
+
+fn wikilinks(source: &Path, text: &str, line_no: usize, links: &mut Vec<Link>) {
+    let mut pos = 0;
+    while let Some(start) = text[pos..].find("[[").map(|i| pos + i) {
+        let Some(len) = text[start + 2..].find("]]") else {
+            break;
+        };
+        let inner = &text[start + 2..start + 2 + len];
+        pos = start + 2 + len + 2;
+
+        // `[[note#heading|alias]]` links to `note`
+        let target = inner.split(['|', '#']).next().unwrap_or_default().trim();
+        let target = target.strip_suffix(".md").unwrap_or(target);
+        if target.is_empty() || inner.contains('\n') {
+            continue;
+        }
+
+        let embed = text[..start].ends_with('!');
+        links.push(Link {
+            source: source.to_path_buf(),
+            target: target.to_string(),
+            kind: if embed {
+                LinkKind::Embed
+            } else {
+                LinkKind::Wiki
+            },
+            line_no: line_no + text[..start].matches('\n').count(),
+        });
+    }
+}
+
+fn markdown_link(source: &Path, url: &str, line_no: usize) -> Option<Link> {
+    let is_external = url.contains("://") || url.starts_with("mailto:");
+    let path = url.split('#').next()?.replace("%20", " ");
+    if is_external || path.is_empty() {
+        return None;
+    }
+
+    let dir = source.parent()?;
+    Some(Link {
+        source: source.to_path_buf(),
+        target: normalize(&dir.join(path)).to_string_lossy().to_string(),
+        kind: LinkKind::Markdown,
+        line_no,
+    })
+}
+
+fn collect(source: &Path, node: &Node, links: &mut Vec<Link>) {
+    let line_no = node.position().map(|p| p.start.line).unwrap_or_default();
+    match node {
+        Node::Text(text) => wikilinks(source, &text.value, line_no, links),
+        Node::Link(link) => links.extend(markdown_link(source, &link.url, line_no)),
+        _ => {}
+    }
+    for child in node.children().into_iter().flatten() {
+        collect(source, child, links);
+    }
+}
+
+/// Extracts every wiki link, embed and relative Markdown link from a note
+pub fn extract(source: &Path, ast: &Node) -> Vec<Link> {
+    let mut links = vec![];
+    collect(source, ast, &mut links);
+    links
+}
+
+/// The indexed notes by the names links use for them, built once to resolve many links
+pub struct Notes<'a> {
+    /// Exact paths, and paths without their extension
+    paths: HashMap<PathBuf, &'a PathBuf>,
+    /// Lowercase paths without their extension, and every trailing part of them
+    /// (`b/note` and `note` for `/a/b/note.md`), each to the shortest matching path
+    names: HashMap<String, &'a PathBuf>,
+}
+
+impl<'a> Notes<'a> {
+    pub fn new(notes: &'a [PathBuf]) -> Self {
+        let mut paths: HashMap<PathBuf, &PathBuf> =
+            notes.iter().map(|note| (note.clone(), note)).collect();
+        let mut names: HashMap<String, &PathBuf> = HashMap::new();
+        for note in notes {
+            let stem = note.with_extension("");
+            let name = stem.to_string_lossy().to_lowercase();
+            paths.entry(stem).or_insert(note);
+
+            let suffixes = name.match_indices('/').map(|(i, _)| &name[i + 1..]);
+            for key in std::iter::once(name.as_str()).chain(suffixes) {
+                let best = names.entry(key.to_string()).or_insert(note);
+                if note.components().count() < best.components().count() {
+                    *best = note;
+                }
+            }
+        }
+        Self { paths, names }
+    }
+
+    /// Finds the indexed note a link points to, if any
+    pub fn resolve(&self, link: &Link) -> Option<&'a PathBuf> {
+        match link.kind {
+            LinkKind::Markdown => self.paths.get(Path::new(&link.target)).copied(),
+            // Like Obsidian, `[[note]]` matches any `note.md` and the shortest path wins
+            LinkKind::Wiki | LinkKind::Embed => {
+                self.names.get(&link.target.to_lowercase()).copied()
+            }
+        }
+    }
+}
+
+pub fn backlinks(file: &Path, config: &MindmapConfig) -> Result<Vec<Link>> {
+    let file = std::path::absolute(file)?;
+    let notes = database::get_paths(config)?;
+    let lookup = Notes::new(&notes);
+    let links = database::get_links(config)?
+        .into_iter()
+        .filter(|link| lookup.resolve(link) == Some(&file))
+        .collect();
+    Ok(links)
+}
+
+/// Notes no other note links to
+pub fn orphans(config: &MindmapConfig) -> Result<Vec<PathBuf>> {
+    let notes = database::get_paths(config)?;
+    let lookup = Notes::new(&notes);
+    let linked: HashSet<&PathBuf> = database::get_links(config)?
+        .iter()
+        .filter_map(|link| {
+            lookup
+                .resolve(link)
+                .filter(|target| **target != link.source)
+        })
+        .collect();
+    let orphans = notes
+        .iter()
+        .filter(|note| !linked.contains(note))
+        .cloned()
+        .collect();
+    Ok(orphans)
+}
+
+pub fn graph(config: &MindmapConfig) -> Result<String> {
+    let notes = database::get_paths(config)?;
+    let lookup = Notes::new(&notes);
+    let edges = database::get_links(config)?
+        .into_iter()
+        .map(|link| GraphEdge {
+            target: lookup.resolve(&link).cloned(),
+            source: link.source,
+            raw_target: link.target,
+            kind: link.kind,
+            line_no: link.line_no,
+        })
+        .collect();
+    let graph = Graph {
+        nodes: notes,
+        edges,
+    };
+    Ok(serde_json::to_string(&graph)?)
+}
+
+pub fn format_links(links: &[Link], format: OutputFormat) -> String {
+    match format {
+        OutputFormat::Json => serde_json::to_string(links).unwrap(),
+        OutputFormat::Raw => links
+            .iter()
+            .map(|l| format!("{}:{}\n", l.source.display(), l.line_no))
+            .collect(),
+        OutputFormat::List => links
+            .iter()
+            .map(|l| {
+                let title = format!("{}:{}", l.source.display(), l.line_no);
+                format!("{} ({} link to {})", title.blue(), l.kind, l.target)
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+    }
+}
+
+pub fn format_paths(paths: &[PathBuf], format: OutputFormat) -> String {
+    match format {
+        OutputFormat::Json => serde_json::to_string(paths).unwrap(),
+        OutputFormat::Raw => paths.iter().map(|p| format!("{}\n", p.display())).collect(),
+        OutputFormat::List => paths
+            .iter()
+            .map(|p| p.display().to_string().blue().to_string())
+            .collect::<Vec<_>>()
+            .join("\n"),
+    }
+}
@@ -3,11 +3,18 @@ use colored::Colorize;
 use log::LevelFilter;
 use mindmap::{
     config::{get_render_config, MindmapConfig},
-    database, files,
+    database, duplicates,
+    files::{self, ReportFormat},
     formatter::OutputFormat,
-    search,
+    index::VectorIndex,
+    links,
+    map::{self, MapOptions},
+    query,
+    search::{self, SearchOptions},
     server::Server,
     setup,
+    similar::{self, SimilarTarget},
+    topics,
     watcher::MindmapWatcher,
 };
 use std::path::PathBuf;
@@ -27,11 +34,27 @@ enum Command {
     /// Watches your MindMap directory for changes
     Watch,
 
-    /// Recomputes your entire MindMap
+    /// Recomputes the new and changed files in your MindMap
     RecomputeAll {
         /// Skip confirmation
         #[arg(short, long, action)]
         yes: bool,
+
+        /// Only show which files would be reindexed or removed and how long it would take
+        #[arg(long, action)]
+        dry_run: bool,
+
+        /// Reindex unchanged files too. Model or chunking changes already reindex everything
+        #[arg(long, action)]
+        force: bool,
+
+        /// The format of the final report
+        #[arg(value_enum, short, long, default_value = "text")]
+        report: ReportFormat,
+
+        /// Exit with a non-zero code if any file failed to index
+        #[arg(long, action)]
+        fail_on_error: bool,
     },
 
     /// Recomputes a specific file
@@ -42,14 +65,97 @@ enum Command {
 
     /// Queries the MindMap for items
     Query {
-        /// The idea to search for
+        /// The idea to search for, e.g. `deploy rollback tag:infra -tag:archive "exact phrase"`
+        /// or `jaguar +"big cats"^2 ~cars`. `-word` drops every block containing the word,
+        /// while `~word` only ranks blocks close to its meaning lower
         query: String,
 
+        #[command(flatten)]
+        opts: Box<SearchOptions>,
+
+        /// The output format
+        #[arg(value_enum, short, long, default_value = "list")]
+        format: OutputFormat,
+    },
+
+    /// Finds the notes closest to a note, or to the blocks on some of its lines
+    Similar {
+        /// `file`, `file:line` or `file:start:end`
+        target: SimilarTarget,
+
+        #[command(flatten)]
+        opts: Box<SearchOptions>,
+
+        /// The output format
+        #[arg(value_enum, short, long, default_value = "list")]
+        format: OutputFormat,
+    },
+
+    /// Lists the notes that link to a file
+    Backlinks {
+        /// The file to find backlinks for
+        file: PathBuf,
+
+        /// The output format
+        #[arg(value_enum, short, long, default_value = "list")]
+        format: OutputFormat,
+    },
+
+    /// Lists the notes no other note links to
+    Orphans {
         /// The output format
         #[arg(value_enum, short, long, default_value = "list")]
         format: OutputFormat,
     },
 
+    /// Finds blocks, or whole notes, so similar they are likely copies of each other
+    Duplicates {
+        /// Only report pairs scoring at least this much
+        #[arg(short, long, default_value_t = 0.95)]
+        threshold: f32,
+
+        /// Compare whole notes rather than blocks
+        #[arg(short, long)]
+        notes: bool,
+
+        /// How many of the closest items of each one are checked
+        #[arg(long, default_value_t = 10)]
+        neighbors: usize,
+
+        /// The output format
+        #[arg(value_enum, short, long, default_value = "list")]
+        format: OutputFormat,
+    },
+
+    /// Clusters the notes into topics labeled by their keywords
+    Topics {
+        /// How many topics to find, based on the number of notes if not given
+        #[arg(short = 'k', long)]
+        topics: Option<usize>,
+
+        /// Also write the topics as a Markdown index note to this file
+        #[arg(long)]
+        index: Option<PathBuf>,
+
+        /// Overwrite the index file even if it isn't an earlier index note
+        #[arg(long, action, requires = "index")]
+        force: bool,
+
+        /// The output format
+        #[arg(value_enum, short, long, default_value = "list")]
+        format: OutputFormat,
+    },
+
+    /// Draws your notes on a 2D map, similar notes close together, as an HTML page
+    Map {
+        #[command(flatten)]
+        opts: MapOptions,
+
+        /// Where to write the page
+        #[arg(short, long, default_value = "mindmap.html")]
+        output: PathBuf,
+    },
+
     /// Starts the MindMap server
     Server,
 }
@@ -69,35 +175,121 @@ fn main() -> anyhow::Result<()> {
         Command::Setup => setup::setup()?,
         Command::Watch => {
             log::info!("Starting watcher");
-            let mut mm_watcher = MindmapWatcher::new(config);
+            let mut mm_watcher = MindmapWatcher::new(config)?;
             mm_watcher.watch()?;
         }
-        Command::RecomputeAll { yes } => {
+        Command::RecomputeAll {
+            yes,
+            dry_run,
+            force,
+            report,
+            fail_on_error,
+        } => {
+            let mut plan = files::plan(&config, force)?;
+            if dry_run {
+                plan.estimate(&config)?;
+                println!("{}", plan.format(report));
+                return Ok(());
+            }
+
             let mut confirmed = true;
-            if !yes {
-                confirmed = inquire::Confirm::new("Are you sure you want to recompute all files?")
+            if !yes && !plan.is_up_to_date() {
+                eprintln!("{}", plan.summary());
+                confirmed = inquire::Confirm::new("Are you sure you want to reindex these files?")
                     .with_default(false)
                     .prompt()?;
             }
 
             if !confirmed {
-                log::info!("Aborting recompute all");
-                println!("{}", "Aborting recompute all".red());
+                log::info!("Aborting reindex");
+                println!("{}", "Aborting reindex".red());
                 return Ok(());
             }
 
-            log::info!("Recomputing all files");
-            println!("{}", "Recomputing all files...".blue());
-            files::recompute_all(&config)?;
+            log::info!("Reindexing files");
+            eprintln!("{}", "Reindexing files...".blue());
+            let index_report = files::recompute_all(&config, &plan)?;
+            println!("{}", index_report.format(report));
+
+            if fail_on_error && !index_report.errors.is_empty() {
+                anyhow::bail!("{} files failed to index", index_report.errors.len());
+            }
         }
         Command::RecomputeFile { file } => {
             log::info!("Recomputing file: {:?}", file);
             println!("{}: {:?}", "Recomputing file".blue(), file);
-            files::recompute_file(&file, &config)?;
+            let mut index = VectorIndex::open(&config)?;
+            files::recompute_file(&file, &mut index, &config)?;
         }
-        Command::Query { query, format } => {
+        Command::Query {
+            query,
+            mut opts,
+            format,
+        } => {
             log::info!("Searching for: {}", query);
-            search::search(&query, &config, format)?;
+            let parsed = match query::parse(&query) {
+                Ok(parsed) => parsed,
+                Err(err) => {
+                    // Shown once, with a caret under the problem
+                    eprintln!("{}", err.show(&query).red());
+                    std::process::exit(1);
+                }
+            };
+            let text = parsed.text.clone();
+            parsed.merge_into(&mut opts);
+            search::search(&text, &opts, &config, format)?;
+        }
+        Command::Similar {
+            target,
+            opts,
+            format,
+        } => {
+            log::info!("Finding notes similar to: {}", target);
+            similar::similar(&target, &opts, &config, format)?;
+        }
+        Command::Backlinks { file, format } => {
+            log::info!("Finding backlinks for: {:?}", file);
+            let backlinks = links::backlinks(&file, &config)?;
+            println!("{}", links::format_links(&backlinks, format));
+        }
+        Command::Orphans { format } => {
+            log::info!("Finding orphan notes");
+            let orphans = links::orphans(&config)?;
+            println!("{}", links::format_paths(&orphans, format));
+        }
+        Command::Duplicates {
+            threshold,
+            notes,
+            neighbors,
+            format,
+        } => {
+            log::info!("Finding duplicates above {}", threshold);
+            let clusters = match notes {
+                true => duplicates::duplicate_notes(threshold, neighbors, &config)?,
+                false => duplicates::duplicate_blocks(threshold, neighbors, &config)?,
+            };
+            println!("{}", duplicates::format_clusters(&clusters, format));
+        }
+        Command::Topics {
+            topics,
+            index,
+            force,
+            format,
+        } => {
+            log::info!("Finding topics");
+            let topics = topics::topics(topics, &config)?;
+            if let Some(index) = index {
+                topics::write_index(&topics, &index, force, &config)?;
+                println!("{}: {:?}", "Wrote topic index".green(), index);
+            }
+            println!("{}", topics::format_topics(&topics, format));
+        }
+        Command::Map { opts, output } => {
+            log::info!("Drawing map to {:?}", output);
+            println!("{}", "Projecting embeddings...".blue());
+            let html = map::map(&opts, &config)?;
+            std::fs::write(&output, html)?;
+            println!("{}: {:?}", "Wrote map".green(), output);
         }
         Command::Server => {
             Server::start(&config)?;
@@ -0,0 +1,559 @@
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{bail, Result};
+use clap::{Args, ValueEnum};
+use rayon::prelude::*;
+use rust_bert::pipelines::sentence_embeddings::Embedding;
+
+use crate::{
+    config::MindmapConfig,
+    database,
+    embeddings::{dot, normalize},
+    utils::Rng,
+};
+
+/// Opens the note in the browser, `{path}` and `{line}` are filled in for every point
+pub const DEFAULT_LINK: &str = "file://{path}";
+/// Schemes links may use, which open notes rather than run anything in the page
+const LINK_SCHEMES: [&str; 3] = ["file", "vscode", "obsidian"];
+/// Exact t-SNE keeps every pair of points in memory, past this PCA is used instead
+const MAX_TSNE_POINTS: usize = 2000;
+/// Dimensions kept by PCA before running t-SNE
+const PCA_DIMS: usize = 30;
+const POWER_ITERATIONS: usize = 30;
+/// Roughly how many neighbors each point keeps close in the map
+const PERPLEXITY: f32 = 30.0;
+const TSNE_ITERATIONS: usize = 1000;
+/// Iterations pulling neighbors extra close at first, so clusters form before settling
+const EXAGGERATION_ITERATIONS: usize = 250;
+const EARLY_EXAGGERATION: f32 = 12.0;
+/// Side of the square the map is drawn in
+const SIZE: f32 = 800.0;
+const MARGIN: f32 = 20.0;
+/// Tableau 10
+const PALETTE: [&str; 10] = [
+    "#4e79a7", "#f28e2b", "#e15759", "#76b7b2", "#59a14f", "#edc948", "#b07aa1", "#ff9da7",
+    "#9c755f", "#bab0ac",
+];
+
+/// What gives points their color
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash, ValueEnum)]
+pub enum ColorBy {
+    /// The folder of the note, from its root
+    #[default]
+    Folder,
+    /// The first tag of the note
+    Tag,
+}
+
+impl FromStr for ColorBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "folder" => Ok(ColorBy::Folder),
+            "tag" => Ok(ColorBy::Tag),
+            _ => Err("Invalid color".to_string()),
+        }
+    }
+}
+
+impl Display for ColorBy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ColorBy::Folder => write!(f, "folder"),
+            ColorBy::Tag => write!(f, "tag"),
+        }
+    }
+}
+
+/// How embeddings are brought down to two dimensions
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash, ValueEnum)]
+pub enum Projection {
+    /// Keeps neighbors together, slow past a thousand points
+    #[default]
+    Tsne,
+    /// Keeps the directions notes differ the most along, fast but blurrier
+    Pca,
+}
+
+impl FromStr for Projection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tsne" => Ok(Projection::Tsne),
+            "pca" => Ok(Projection::Pca),
+            _ => Err("Invalid projection".to_string()),
+        }
+    }
+}
+
+impl Display for Projection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Projection::Tsne => write!(f, "tsne"),
+            Projection::Pca => write!(f, "pca"),
+        }
+    }
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct MapOptions {
+    /// Plot every block instead of every note
+    #[arg(long, action)]
+    pub blocks: bool,
+
+    /// What gives points their color
+    #[arg(value_enum, long, default_value = "folder")]
+    pub color_by: ColorBy,
+
+    /// How embeddings are projected, t-SNE falls back to PCA past 2000 points
+    #[arg(value_enum, long, default_value = "tsne")]
+    pub projection: Projection,
+
+    /// Where clicking a point leads, e.g. `vscode://file{path}:{line}`. Only file:,
+    /// vscode: and obsidian: links are allowed
+    #[arg(long, default_value = DEFAULT_LINK, value_parser = parse_link)]
+    pub link: String,
+}
+
+impl Default for MapOptions {
+    fn default() -> Self {
+        Self {
+            blocks: false,
+            color_by: ColorBy::default(),
+            projection: Projection::default(),
+            link: DEFAULT_LINK.to_string(),
+        }
+    }
+}
+
+/// Checks a link template opens notes with one of `LINK_SCHEMES`, so no `javascript:`
+/// link ends up in the page
+pub fn parse_link(link: &str) -> Result<String, String> {
+    match link.split_once(':') {
+        Some((scheme, _)) if LINK_SCHEMES.contains(&scheme.to_lowercase().as_str()) => {
+            Ok(link.to_string())
+        }
+        _ => Err(format!(
+            "Invalid link '{}', expected a file:, vscode: or obsidian: link",
+            link
+        )),
+    }
+}
+
+/// A note or block on the map
+struct Point {
+    path: PathBuf,
+    /// First and last line of a block
+    lines: Option<(usize, usize)>,
+    category: String,
+    x: f32,
+    y: f32,
+}
+
+/// The `dims` directions along which points vary the most, found by power iteration,
+/// and the coordinates of every point along them
+fn pca(points: &[Embedding], dims: usize) -> Vec<Vec<f32>> {
+    let len = points[0].len();
+    let rows: Vec<&Embedding> = points.iter().collect();
+    let mean = crate::embeddings::mean(&rows).expect("There should be points");
+    let centered: Vec<Vec<f32>> = points
+        .iter()
+        .map(|p| p.iter().zip(&mean).map(|(x, m)| x - m).collect())
+        .collect();
+
+    let mut rng = Rng::default();
+    let mut components: Vec<Embedding> = vec![];
+    for _ in 0..dims.min(len).min(points.len()) {
+        let mut v: Embedding = (0..len).map(|_| rng.next_f32() - 0.5).collect();
+        for _ in 0..POWER_ITERATIONS {
+            let projections: Vec<f32> = centered.par_iter().map(|x| dot(x, &v)).collect();
+            let mut next = vec![0.0; len];
+            for (x, projection) in centered.iter().zip(projections) {
+                next.iter_mut()
+                    .zip(x)
+                    .for_each(|(n, x)| *n += projection * x);
+            }
+            // Stay clear of the directions already found
+            for component in &components {
+                let overlap = dot(&next, component);
+                next.iter_mut()
+                    .zip(component)
+                    .for_each(|(n, c)| *n -= overlap * c);
+            }
+            v = normalize(next);
+        }
+        components.push(v);
+    }
+
+    centered
+        .iter()
+        .map(|x| components.iter().map(|c| dot(x, c)).collect())
+        .collect()
+}
+
+fn squared_distance(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| (x - y) * (x - y)).sum()
+}
+
+/// How likely every point is to pick each other one as its neighbor, each neighborhood
+/// sized to hold about `PERPLEXITY` points
+fn affinities(points: &[Vec<f32>]) -> Vec<f32> {
+    let n = points.len();
+    let target = PERPLEXITY.min((n - 1) as f32 / 3.0).ln();
+    let rows: Vec<Vec<f32>> = (0..n)
+        .into_par_iter()
+        .map(|i| {
+            let distances: Vec<f32> = points
+                .iter()
+                .map(|other| squared_distance(&points[i], other))
+                .collect();
+            // Shifting distances keeps the closest neighbor from underflowing
+            let closest = (0..n)
+                .filter(|&j| j != i)
+                .map(|j| distances[j])
+                .fold(f32::INFINITY, f32::min);
+
+            // Binary search for the precision giving the target entropy
+            let (mut beta, mut low, mut high) = (1.0f32, 0.0f32, f32::INFINITY);
+            let mut row = vec![0.0; n];
+            for _ in 0..50 {
+                for (j, p) in row.iter_mut().enumerate() {
+                    *p = if j == i {
+                        0.0
+                    } else {
+                        (-(distances[j] - closest) * beta).exp()
+                    };
+                }
+                let sum: f32 = row.iter().sum::<f32>().max(f32::MIN_POSITIVE);
+                row.iter_mut().for_each(|p| *p /= sum);
+                let spread: f32 = row
+                    .iter()
+                    .zip(&distances)
+                    .map(|(p, d)| p * (d - closest))
+                    .sum();
+                let entropy = sum.ln() + beta * spread;
+                if (entropy - target).abs() < 1e-5 {
+                    break;
+                }
+                if entropy > target {
+                    low = beta;
+                    beta = if high.is_infinite() {
+                        beta * 2.0
+                    } else {
+                        (beta + high) / 2.0
+                    };
+                } else {
+                    high = beta;
+                    beta = (beta + low) / 2.0;
+                }
+            }
+            row
+        })
+        .collect();
+
+    let mut p = vec![0.0; n * n];
+    for i in 0..n {
+        for j in 0..n {
+            p[i * n + j] = ((rows[i][j] + rows[j][i]) / (2.0 * n as f32)).max(1e-12);
+        }
+    }
+    p
+}
+
+/// Exact t-SNE, starting from `init`. Quadratic in the number of points.
+fn tsne(points: &[Vec<f32>], init: Vec<[f32; 2]>) -> Vec<[f32; 2]> {
+    let n = points.len();
+    let p = affinities(points);
+    let learning_rate = (n as f32 / EARLY_EXAGGERATION).max(50.0);
+    let mut y = init;
+    let mut velocity = vec![[0.0f32; 2]; n];
+    let mut gains = vec![[1.0f32; 2]; n];
+
+    for iteration in 0..TSNE_ITERATIONS {
+        let (exaggeration, momentum) = if iteration < EXAGGERATION_ITERATIONS {
+            (EARLY_EXAGGERATION, 0.5)
+        } else {
+            (1.0, 0.8)
+        };
+
+        // Student-t similarities between every pair of points on the map
+        let q: Vec<f32> = (0..n * n)
+            .into_par_iter()
+            .map(|k| {
+                let (i, j) = (k / n, k % n);
+                if i == j {
+                    0.0
+                } else {
+                    1.0 / (1.0 + squared_distance(&y[i], &y[j]))
+                }
+            })
+            .collect();
+        let total: f32 = q.par_iter().sum();
+
+        let gradients: Vec<[f32; 2]> = (0..n)
+            .into_par_iter()
+            .map(|i| {
+                let mut gradient = [0.0; 2];
+                for j in 0..n {
+                    let k = i * n + j;
+                    let force = 4.0 * (exaggeration * p[k] - q[k] / total) * q[k];
+                    gradient[0] += force * (y[i][0] - y[j][0]);
+                    gradient[1] += force * (y[i][1] - y[j][1]);
+                }
+                gradient
+            })
+            .collect();
+
+        for i in 0..n {
+            for d in 0..2 {
+                // Speed up along directions the gradient keeps pointing to
+                gains[i][d] = if gradients[i][d].signum() != velocity[i][d].signum() {
+                    gains[i][d] + 0.2
+                } else {
+                    (gains[i][d] * 0.8).max(0.01)
+                };
+                velocity[i][d] =
+                    momentum * velocity[i][d] - learning_rate * gains[i][d] * gradients[i][d];
+                y[i][d] += velocity[i][d];
+            }
+        }
+        let mean = y.iter().fold([0.0; 2], |m, p| [m[0] + p[0], m[1] + p[1]]);
+        for point in y.iter_mut() {
+            point[0] -= mean[0] / n as f32;
+            point[1] -= mean[1] / n as f32;
+        }
+    }
+    y
+}
+
+/// Places every embedding on a plane, similar ones close together
+fn project(embeddings: Vec<Embedding>, projection: Projection) -> Vec<[f32; 2]> {
+    let points: Vec<Embedding> = embeddings.into_iter().map(normalize).collect();
+    let use_tsne = projection == Projection::Tsne && points.len() > 3;
+    if use_tsne && points.len() > MAX_TSNE_POINTS {
+        log::warn!("{} points are too many for t-SNE, using PCA", points.len());
+    }
+    if !use_tsne || points.len() > MAX_TSNE_POINTS {
+        return pca(&points, 2)
+            .into_iter()
+            .map(|c| {
+                [
+                    c.first().copied().unwrap_or(0.0),
+                    c.get(1).copied().unwrap_or(0.0),
+                ]
+            })
+            .collect();
+    }
+
+    let reduced = pca(&points, PCA_DIMS);
+    // Start from the PCA layout squeezed tight, so runs are deterministic
+    let spread = (reduced.iter().map(|c| c[0] * c[0]).sum::<f32>() / reduced.len() as f32).sqrt();
+    let scale = if spread > 0.0 { 1e-4 / spread } else { 1.0 };
+    let init = reduced
+        .iter()
+        .map(|c| [c[0] * scale, c.get(1).copied().unwrap_or(0.0) * scale])
+        .collect();
+    tsne(&reduced, init)
+}
+
+fn category(
+    path: &Path,
+    color_by: ColorBy,
+    tags: &HashMap<PathBuf, Vec<String>>,
+    config: &MindmapConfig,
+) -> String {
+    match color_by {
+        ColorBy::Folder => {
+            let root = config.get_root(path).path;
+            let folder = path.parent().unwrap_or(Path::new(""));
+            let relative = folder.strip_prefix(&root).unwrap_or(folder);
+            match relative.as_os_str().is_empty() {
+                true => ".".to_string(),
+                false => relative.display().to_string(),
+            }
+        }
+        ColorBy::Tag => tags
+            .get(path)
+            .and_then(|t| t.first())
+            .cloned()
+            .unwrap_or_else(|| "untagged".to_string()),
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Percent-encodes a path for a URL, keeping its slashes
+fn encode_path(path: &Path) -> String {
+    path.to_string_lossy()
+        .bytes()
+        .map(|b| match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// A self-contained HTML page drawing the points as an SVG scatter plot
+fn render(points: &[Point], link: &str) -> String {
+    // Biggest categories first, they get their own color
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for point in points {
+        *counts.entry(point.category.as_str()).or_default() += 1;
+    }
+    let mut categories: Vec<(&str, usize)> = counts.into_iter().collect();
+    categories.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    // Past the palette, the smallest categories share the last color
+    let named = match categories.len() > PALETTE.len() {
+        true => PALETTE.len() - 1,
+        false => categories.len(),
+    };
+    let colors: HashMap<&str, &str> = categories
+        .iter()
+        .enumerate()
+        .map(|(i, (category, _))| (*category, PALETTE[i.min(named)]))
+        .collect();
+
+    let bounds = |axis: fn(&Point) -> f32| {
+        points
+            .iter()
+            .map(axis)
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(lo, hi), v| {
+                (lo.min(v), hi.max(v))
+            })
+    };
+    let (min_x, max_x) = bounds(|p| p.x);
+    let (min_y, max_y) = bounds(|p| p.y);
+    // Same scale on both axes, so distances aren't distorted
+    let range = (max_x - min_x).max(max_y - min_y).max(f32::EPSILON);
+    let scale = (SIZE - 2.0 * MARGIN) / range;
+
+    let mut circles = String::new();
+    for point in points {
+        let cx = MARGIN + (point.x - min_x) * scale;
+        let cy = MARGIN + (point.y - min_y) * scale;
+        let title = match point.lines {
+            Some((start, end)) => format!("{}:{}-{}", point.path.display(), start, end),
+            None => point.path.display().to_string(),
+        };
+        let href = link.replace("{path}", &encode_path(&point.path)).replace(
+            "{line}",
+            &point.lines.map_or(1, |(start, _)| start).to_string(),
+        );
+        circles.push_str(&format!(
+            "<a href=\"{}\"><circle cx=\"{:.1}\" cy=\"{:.1}\" r=\"4\" fill=\"{}\"><title>{} ({})</title></circle></a>\n",
+            escape(&href),
+            cx,
+            cy,
+            colors[point.category.as_str()],
+            escape(&title),
+            escape(&point.category),
+        ));
+    }
+
+    let item = |color: &str, name: &str, count: usize| {
+        format!(
+            "<li><span style=\"background: {}\"></span>{} ({})</li>\n",
+            color, name, count
+        )
+    };
+    let mut legend: String = categories[..named]
+        .iter()
+        .enumerate()
+        .map(|(i, (category, count))| item(PALETTE[i], &escape(category), *count))
+        .collect();
+    if named < categories.len() {
+        let others = categories[named..].iter().map(|(_, count)| count).sum();
+        legend.push_str(&item(PALETTE[named], "others", others));
+    }
+
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>MindMap</title>
+<style>
+body {{ display: flex; margin: 0; font-family: sans-serif; }}
+svg {{ flex: 1; height: 100vh; }}
+circle {{ opacity: 0.75; }}
+a:hover circle {{ opacity: 1; stroke: #333; }}
+ul {{ list-style: none; padding: 1em; min-width: 12em; }}
+li span {{ display: inline-block; width: 0.8em; height: 0.8em; margin-right: 0.4em; border-radius: 50%; }}
+</style>
+</head>
+<body>
+<svg viewBox="0 0 {size} {size}" preserveAspectRatio="xMidYMid meet">
+{circles}</svg>
+<ul>
+{legend}</ul>
+</body>
+</html>
+"#,
+        size = SIZE,
+        circles = circles,
+        legend = legend,
+    )
+}
+
+/// An HTML page showing the notes, or their blocks, on a plane with similar ones close
+pub fn map(opts: &MapOptions, config: &MindmapConfig) -> Result<String> {
+    let tags: HashMap<PathBuf, Vec<String>> = match opts.color_by {
+        ColorBy::Tag => database::get_files(config)?
+            .into_iter()
+            .map(|(path, record)| (path, record.tags))
+            .collect(),
+        ColorBy::Folder => HashMap::new(),
+    };
+    // Placed once every embedding is projected
+    let point = |path: PathBuf, lines| Point {
+        category: category(&path, opts.color_by, &tags, config),
+        path,
+        lines,
+        x: 0.0,
+        y: 0.0,
+    };
+    let (mut points, embeddings): (Vec<Point>, Vec<Embedding>) = match opts.blocks {
+        true => database::get_all(config)?
+            .into_iter()
+            .map(|s| {
+                let lines = Some((s.start_line_no, s.end_line_no));
+                (point(s.path, lines), s.embedding)
+            })
+            .unzip(),
+        false => database::get_note_embeddings(config)?
+            .into_iter()
+            .map(|(path, embedding)| (point(path, None), embedding))
+            .unzip(),
+    };
+    if points.is_empty() {
+        bail!("No notes indexed, run `mindmap recompute-all` first");
+    }
+
+    log::info!(
+        "Projecting {} points with {}",
+        points.len(),
+        opts.projection
+    );
+    for (point, [x, y]) in points.iter_mut().zip(project(embeddings, opts.projection)) {
+        point.x = x;
+        point.y = y;
+    }
+    let link = parse_link(&opts.link).map_err(anyhow::Error::msg)?;
+    Ok(render(&points, &link))
+}
@@ -0,0 +1,392 @@
+use std::{
+    fmt::{Display, Formatter},
+    iter::Peekable,
+    str::CharIndices,
+};
+
+use crate::search::{self, SearchFilter, SearchOptions};
+
+/// A phrase embedded on its own and weighed against the rest of the query
+#[derive(Debug, Clone)]
+pub struct QueryPart {
+    pub text: String,
+    /// Negative for parts results should be unlike
+    pub weight: f32,
+}
+
+/// A query split into what gets embedded, what has to appear literally and filters
+#[derive(Debug, Default)]
+pub struct ParsedQuery {
+    /// The free text and phrases, embedded and compared to the notes
+    pub text: String,
+    /// The `+parts` and `~parts`, embedded apart from the text
+    pub parts: Vec<QueryPart>,
+    /// Phrases every result has to contain
+    pub phrases: Vec<String>,
+    /// Words and phrases no result may contain
+    pub excluded: Vec<String>,
+    pub filter: SearchFilter,
+}
+
+#[derive(Debug)]
+pub struct QueryError {
+    /// Character offset in the query where the problem starts
+    pub position: usize,
+    pub message: String,
+}
+
+impl Display for QueryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position + 1)
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+impl QueryError {
+    /// The query with a caret under the problem, for the terminal
+    pub fn show(&self, query: &str) -> String {
+        format!("{}\n{}^ {}", query, " ".repeat(self.position), self.message)
+    }
+}
+
+struct Token {
+    /// Character offset of the token
+    position: usize,
+    negated: bool,
+    /// The signed weight of a `+part` or `~part`
+    part: Option<f32>,
+    key: Option<String>,
+    value: String,
+    quoted: bool,
+}
+
+const KEYS: [&str; 8] = [
+    "tag",
+    "path",
+    "ext",
+    "lang",
+    "after",
+    "before",
+    "created_after",
+    "created_before",
+];
+
+struct Tokenizer<'a> {
+    query: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+}
+
+impl<'a> Tokenizer<'a> {
+    fn position(&self, byte: usize) -> usize {
+        self.query[..byte].chars().count()
+    }
+
+    fn error(&self, byte: usize, message: &str) -> QueryError {
+        QueryError {
+            position: self.position(byte),
+            message: message.to_string(),
+        }
+    }
+
+    /// Reads a `"quoted value"`, the opening quote being next
+    fn quoted(&mut self) -> Result<String, QueryError> {
+        let (start, _) = self.chars.next().expect("Quote should be next");
+        let mut value = String::new();
+        for (_, c) in self.chars.by_ref() {
+            if c == '"' {
+                return Ok(value);
+            }
+            value.push(c);
+        }
+        Err(self.error(start, "Unterminated quote"))
+    }
+
+    fn word(&mut self) -> String {
+        let mut word = String::new();
+        while let Some((_, c)) = self.chars.next_if(|(_, c)| !c.is_whitespace() && *c != '"') {
+            word.push(c);
+        }
+        word
+    }
+
+    /// Reads the text of a `+part` or `~part` and its `^weight`, 1 if not given
+    fn part(&mut self) -> Result<(String, f32), QueryError> {
+        let (text, weight) = match self.chars.peek() {
+            Some((_, '"')) => {
+                let text = self.quoted()?;
+                let weight = self
+                    .chars
+                    .next_if(|(_, c)| *c == '^')
+                    .map(|(at, _)| (at, self.word()));
+                (text, weight)
+            }
+            _ => {
+                let start = self.chars.peek().map_or(self.query.len(), |(i, _)| *i);
+                let word = self.word();
+                match word.rsplit_once('^') {
+                    Some((text, weight)) => {
+                        let at = start + text.len();
+                        (text.to_string(), Some((at, weight.to_string())))
+                    }
+                    None => (word, None),
+                }
+            }
+        };
+
+        let Some((at, weight)) = weight else {
+            return Ok((text, 1.0));
+        };
+        match weight.parse::<f32>() {
+            Ok(w) if w.is_finite() && w > 0.0 => Ok((text, w)),
+            _ => Err(self.error(at, &format!("Invalid weight '{}'", weight))),
+        }
+    }
+
+    fn next_token(&mut self) -> Result<Option<Token>, QueryError> {
+        while self.chars.next_if(|(_, c)| c.is_whitespace()).is_some() {}
+        let Some(&(start, first)) = self.chars.peek() else {
+            return Ok(None);
+        };
+
+        let mut token = Token {
+            position: self.position(start),
+            negated: false,
+            part: None,
+            key: None,
+            value: String::new(),
+            quoted: false,
+        };
+        if first == '-' {
+            self.chars.next();
+            token.negated = self.chars.peek().is_some_and(|(_, c)| !c.is_whitespace());
+            if !token.negated {
+                token.value = "-".to_string();
+                return Ok(Some(token));
+            }
+        }
+
+        // `~` only starts a part before a word or a quote, so `~/notes` stays text
+        let tilde_part = first == '~'
+            && self.query[start + 1..]
+                .chars()
+                .next()
+                .is_some_and(|c| c.is_alphanumeric() || c == '_' || c == '"');
+        if first == '+' || tilde_part {
+            self.chars.next();
+            if self.chars.peek().is_none_or(|(_, c)| c.is_whitespace()) {
+                token.value = first.to_string();
+                return Ok(Some(token));
+            }
+            let sign = if first == '+' { 1.0 } else { -1.0 };
+            let (text, weight) = self.part()?;
+            token.value = text;
+            token.part = Some(sign * weight);
+            return Ok(Some(token));
+        }
+
+        if let Some(&(quote, '"')) = self.chars.peek() {
+            token.value = self.quoted()?;
+            token.quoted = true;
+            if token.value.trim().is_empty() {
+                return Err(self.error(quote, "Empty phrase"));
+            }
+            return Ok(Some(token));
+        }
+
+        let word = self.word();
+        match word.split_once(':') {
+            Some((key, value)) if KEYS.contains(&key) => {
+                token.key = Some(key.to_string());
+                if value.is_empty() && self.chars.peek().is_some_and(|(_, c)| *c == '"') {
+                    token.value = self.quoted()?;
+                    token.quoted = true;
+                } else {
+                    token.value = value.to_string();
+                }
+            }
+            _ => token.value = word,
+        }
+        Ok(Some(token))
+    }
+}
+
+fn date(token: &Token) -> Result<Option<i64>, QueryError> {
+    search::parse_date_arg(&token.value)
+        .map(Some)
+        .map_err(|message| QueryError {
+            position: token.position,
+            message,
+        })
+}
+
+/// Parses queries like `deploy rollback tag:infra path:work/ -tag:archive "exact phrase" after:2024-01-01`
+/// or `boats +sailing^2 ~"motor boats"`
+pub fn parse(query: &str) -> Result<ParsedQuery, QueryError> {
+    let mut tokenizer = Tokenizer {
+        query,
+        chars: query.char_indices().peekable(),
+    };
+    let mut parsed = ParsedQuery::default();
+    let mut text = vec![];
+
+    while let Some(token) = tokenizer.next_token()? {
+        let error = |message: String| QueryError {
+            position: token.position,
+            message,
+        };
+
+        if let Some(weight) = token.part {
+            if token.value.trim().is_empty() {
+                return Err(error("Missing text for the part".to_string()));
+            }
+            parsed.parts.push(QueryPart {
+                text: token.value,
+                weight,
+            });
+            continue;
+        }
+
+        let Some(key) = &token.key else {
+            match (token.negated, token.quoted) {
+                (true, _) => parsed.excluded.push(token.value),
+                (false, true) => {
+                    text.push(token.value.clone());
+                    parsed.phrases.push(token.value);
+                }
+                (false, false) => text.push(token.value),
+            }
+            continue;
+        };
+
+        if token.value.is_empty() {
+            return Err(error(format!("Missing value for `{}:`", key)));
+        }
+        let filter = &mut parsed.filter;
+        match (key.as_str(), token.negated) {
+            ("tag", false) => filter.tags.push(token.value.clone()),
+            ("tag", true) => filter.exclude_tags.push(token.value.clone()),
+            ("path", false) => filter.paths.push(token.value.clone()),
+            ("path", true) => filter.exclude_paths.push(token.value.clone()),
+            ("ext", false) => filter.extensions.push(token.value.clone()),
+            ("lang", false) => filter.lang = Some(token.value.clone()),
+            ("after", false) => filter.modified_after = date(&token)?,
+            ("before", false) => filter.modified_before = date(&token)?,
+            ("created_after", false) => filter.created_after = date(&token)?,
+            ("created_before", false) => filter.created_before = date(&token)?,
+            (key, true) => return Err(error(format!("`{}:` can't be negated", key))),
+            _ => unreachable!("Keys should be known"),
+        }
+    }
+
+    parsed.text = text.join(" ");
+    if parsed.text.trim().is_empty() && !parsed.parts.iter().any(|p| p.weight > 0.0) {
+        let message = match parsed.parts.is_empty() {
+            true => "Nothing to search for besides filters",
+            false => "Nothing to search for besides parts to avoid",
+        };
+        return Err(QueryError {
+            position: 0,
+            message: message.to_string(),
+        });
+    }
+    Ok(parsed)
+}
+
+impl ParsedQuery {
+    /// Adds the constraints from the query to the ones given as flags or parameters
+    pub fn merge_into(self, opts: &mut SearchOptions) {
+        let filter = &mut opts.filter;
+        filter.paths.extend(self.filter.paths);
+        filter.exclude_paths.extend(self.filter.exclude_paths);
+        filter.tags.extend(self.filter.tags);
+        filter.exclude_tags.extend(self.filter.exclude_tags);
+        filter.extensions.extend(self.filter.extensions);
+        filter.lang = self.filter.lang.or(filter.lang.take());
+        filter.modified_after = self.filter.modified_after.or(filter.modified_after);
+        filter.modified_before = self.filter.modified_before.or(filter.modified_before);
+        filter.created_after = self.filter.created_after.or(filter.created_after);
+        filter.created_before = self.filter.created_before.or(filter.created_before);
+        opts.phrases.extend(self.phrases);
+        opts.excluded.extend(self.excluded);
+        opts.parts.extend(self.parts);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_at(query: &str) -> usize {
+        parse(query).expect_err("Query should be rejected").position
+    }
+
+    #[test]
+    fn negation() {
+        let parsed = parse(r#"deploy -rollback -"old plan" -tag:archive - x"#).unwrap();
+        assert_eq!(parsed.text, "deploy - x");
+        assert_eq!(parsed.excluded, ["rollback", "old plan"]);
+        assert_eq!(parsed.filter.exclude_tags, ["archive"]);
+        assert_eq!(error_at("deploy -lang:rust"), 7);
+    }
+
+    #[test]
+    fn quoting() {
+        let parsed =
+            parse(r#""exact phrase" tag:infra path:"my notes/" after:2024-01-01"#).unwrap();
+        assert_eq!(parsed.text, "exact phrase");
+        assert_eq!(parsed.phrases, ["exact phrase"]);
+        assert_eq!(parsed.filter.tags, ["infra"]);
+        assert_eq!(parsed.filter.paths, ["my notes/"]);
+        assert!(parsed.filter.modified_after.is_some());
+        assert_eq!(error_at(r#"deploy "rollback"#), 7);
+        assert_eq!(error_at("deploy tag:"), 7);
+    }
+
+    #[test]
+    fn empty_phrases() {
+        assert_eq!(error_at(r#"deploy """#), 7);
+        assert_eq!(error_at(r#"deploy -"""#), 8);
+        assert_eq!(error_at(r#"deploy " ""#), 7);
+    }
+
+    fn parts(query: &str) -> Vec<(String, f32)> {
+        let parsed = parse(query).unwrap();
+        parsed
+            .parts
+            .into_iter()
+            .map(|p| (p.text, p.weight))
+            .collect()
+    }
+
+    #[test]
+    fn weighted_parts() {
+        assert_eq!(
+            parts(r#"jaguar +"big cats"^2 ~cars"#),
+            [("big cats".to_string(), 2.0), ("cars".to_string(), -1.0)]
+        );
+        assert_eq!(
+            parts("+sailing^0.5 ~engines^3"),
+            [("sailing".to_string(), 0.5), ("engines".to_string(), -3.0)]
+        );
+        assert_eq!(parse(r#"jaguar +"big cats"^2"#).unwrap().text, "jaguar");
+        assert_eq!(error_at("jaguar +cats^0"), 12);
+        assert_eq!(error_at(r#"jaguar +"cats"^x"#), 14);
+        assert_eq!(error_at("~cars"), 0);
+    }
+
+    #[test]
+    fn part_markers() {
+        // `-` excludes words rather than making a negative part
+        let parsed = parse("boats +sailing -engines").unwrap();
+        assert_eq!(parsed.excluded, ["engines"]);
+        assert_eq!(
+            parts("boats +sailing -engines"),
+            [("sailing".to_string(), 1.0)]
+        );
+
+        let parsed = parse("files in ~/notes + ~ ~_draft").unwrap();
+        assert_eq!(parsed.text, "files in ~/notes + ~");
+        assert_eq!(parts("files ~_draft"), [("_draft".to_string(), -1.0)]);
+    }
+}
@@ -0,0 +1,146 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use rust_bert::{
+    bert::{BertConfig, BertForSequenceClassification},
+    pipelines::sentence_embeddings::Embedding,
+    resources::{RemoteResource, ResourceProvider},
+    Config,
+};
+use rust_tokenizers::tokenizer::{BertTokenizer, Tokenizer, TruncationStrategy};
+use tch::{nn::VarStore, Device, Tensor};
+
+use crate::{
+    config::RerankerConfig,
+    embeddings::{dot, normalize},
+    search::SearchResult,
+};
+
+/// Longest query and block pair the cross-encoder reads, in tokens
+const MAX_LENGTH: usize = 512;
+/// The id of `[PAD]` in BERT vocabularies
+const PAD_ID: i64 = 0;
+
+/// Scores a query and a block together, which ranks better than comparing their
+/// embeddings but is too slow for more than the best few results
+pub struct CrossEncoder {
+    tokenizer: BertTokenizer,
+    model: BertForSequenceClassification,
+    device: Device,
+    // Holds the weights of `model`
+    _var_store: VarStore,
+}
+
+/// A file of the configured model, downloaded once when not given locally
+fn resource(config: &RerankerConfig, file: &str) -> Result<PathBuf> {
+    if let Some(dir) = &config.path {
+        return Ok(dir.join(file));
+    }
+    let name = format!("{}/{}", config.model, file);
+    let url = format!(
+        "https://huggingface.co/{}/resolve/main/{}",
+        config.model, file
+    );
+    Ok(RemoteResource::from_pretrained((name.as_str(), url.as_str())).get_local_path()?)
+}
+
+impl CrossEncoder {
+    pub fn new(config: &RerankerConfig) -> Result<Self> {
+        let device = Device::cuda_if_available();
+        let bert_config = BertConfig::from_file(resource(config, "config.json")?);
+        let tokenizer = BertTokenizer::from_file(resource(config, "vocab.txt")?, true, true)?;
+
+        let mut var_store = VarStore::new(device);
+        let model = BertForSequenceClassification::new(var_store.root(), &bert_config)?;
+        var_store.load(resource(config, "rust_model.ot")?)?;
+        Ok(Self {
+            tokenizer,
+            model,
+            device,
+            _var_store: var_store,
+        })
+    }
+
+    /// How relevant each passage is to the query, from 0 to 1
+    pub fn score(&self, query: &str, passages: &[&str]) -> Result<Vec<f32>> {
+        if passages.is_empty() {
+            return Ok(vec![]);
+        }
+        let pairs: Vec<(&str, &str)> = passages.iter().map(|p| (query, *p)).collect();
+        let inputs = self.tokenizer.encode_pair_list(
+            &pairs,
+            MAX_LENGTH,
+            &TruncationStrategy::LongestFirst,
+            0,
+        );
+
+        let max_len = inputs.iter().map(|i| i.token_ids.len()).max().unwrap_or(0);
+        let batch = |rows: Vec<Vec<i64>>| {
+            let rows: Vec<Tensor> = rows
+                .into_iter()
+                .map(|mut row| {
+                    row.resize(max_len, PAD_ID);
+                    Tensor::from_slice(&row)
+                })
+                .collect();
+            Tensor::stack(&rows, 0).to(self.device)
+        };
+        let ids = batch(inputs.iter().map(|i| i.token_ids.clone()).collect());
+        let types = batch(
+            inputs
+                .iter()
+                .map(|i| i.segment_ids.iter().map(|&s| s as i64).collect())
+                .collect(),
+        );
+        let mask = batch(inputs.iter().map(|i| vec![1; i.token_ids.len()]).collect());
+
+        let logits = tch::no_grad(|| {
+            self.model
+                .forward_t(Some(&ids), Some(&mask), Some(&types), None, None, false)
+                .logits
+        });
+        let scores = (0..passages.len())
+            .map(|i| {
+                let logit = logits.double_value(&[i as i64, 0]) as f32;
+                1.0 / (1.0 + (-logit).exp())
+            })
+            .collect();
+        Ok(scores)
+    }
+}
+
+/// Reorders candidates sorted by relevance with Maximal Marginal Relevance: each pick is the
+/// one best balancing its relevance against its similarity to what was already picked.
+/// `lambda` goes from 0 (only diversity) to 1 (only relevance). Stops after `limit` picks.
+pub fn mmr(
+    candidates: Vec<(SearchResult, Embedding)>,
+    lambda: f32,
+    limit: usize,
+) -> Vec<SearchResult> {
+    // Unit length, so dot products are the cosine similarities
+    let mut remaining: Vec<(SearchResult, Embedding)> = candidates
+        .into_iter()
+        .map(|(result, embedding)| (result, normalize(embedding)))
+        .collect();
+    // The highest similarity of every remaining candidate to the ones picked so far
+    let mut max_similarity = vec![f32::NEG_INFINITY; remaining.len()];
+    let mut picked = Vec::with_capacity(limit.min(remaining.len()));
+
+    while !remaining.is_empty() && picked.len() < limit {
+        let marginal = |i: usize| {
+            let redundancy = max_similarity[i].max(0.0);
+            lambda * remaining[i].0.relevance() - (1.0 - lambda) * redundancy
+        };
+        let best = (0..remaining.len())
+            .max_by(|&a, &b| marginal(a).total_cmp(&marginal(b)))
+            .expect("Candidates should remain");
+
+        let (result, embedding) = remaining.swap_remove(best);
+        max_similarity.swap_remove(best);
+        for (i, (_, other)) in remaining.iter().enumerate() {
+            max_similarity[i] = max_similarity[i].max(dot(&embedding, other));
+        }
+        picked.push(result);
+    }
+    picked
+}
@@ -1,90 +1,609 @@
-use std::path::PathBuf;
+use std::{
+    cell::OnceCell,
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+};
 
-use acap::cos::cosine_distance;
-use acap::knn::NearestNeighbors;
-use acap::vp::VpTree;
-use acap::{Distance, Proximity};
-use anyhow::Result;
+use anyhow::{bail, Result};
+use clap::Args;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use rust_bert::pipelines::sentence_embeddings::Embedding;
+use serde::Serialize;
 
 use crate::formatter::{self, OutputFormat};
 use crate::{
-    config::MindmapConfig,
-    database::{self, EmbeddedSentence},
-    embeddings::Model,
+    config::{MindmapConfig, RootConfig},
+    database,
+    embeddings::{self, Model},
+    frontmatter,
+    grouping::{self, Grouping},
+    index::VectorIndex,
+    query::QueryPart,
+    rerank::{self, CrossEncoder},
+    similar::{self, SimilarTarget},
 };
 
+/// How many more neighbors are fetched when results have to contain or avoid some words
+const LEXICAL_OVERSAMPLING: usize = 10;
+/// How many more neighbors are fetched when blocks get merged or grouped by file
+const GROUPING_OVERSAMPLING: usize = 5;
+/// How many more neighbors MMR gets to pick diverse results from
+const MMR_OVERSAMPLING: usize = 4;
+/// The most neighbors fetched, however much the factors above add up to
+const MAX_CANDIDATES: usize = 1000;
+
+/// What a part of a multi-part query adds to the score of a result
+#[derive(Clone, Debug, Serialize)]
+pub struct PartScore {
+    pub text: String,
+    pub weight: f32,
+    /// Cosine similarity of the block to the part
+    pub similarity: f32,
+    /// Share of the score, negative for parts pulling it down
+    pub contribution: f32,
+}
+
 #[derive(Debug)]
 pub struct SearchResult {
     pub path: PathBuf,
+    pub collection: Option<String>,
     pub start_line_no: usize,
+    pub start_col: usize,
+    pub start_offset: usize,
     pub end_line_no: usize,
-    pub distance: f32,
+    pub end_col: usize,
+    pub end_offset: usize,
+    pub breadcrumb: Option<String>,
+    pub lang: Option<String>,
+    /// Cosine similarity to the query, from 0 (unrelated) to 1 (identical)
+    pub score: f32,
+    /// Relevance to the query according to the cross-encoder, from 0 to 1, when re-ranked
+    pub rerank_score: Option<f32>,
+    /// How every part of a multi-part query adds up to the score, empty for other queries
+    pub parts: Vec<PartScore>,
+}
+
+impl SearchResult {
+    /// The best estimate of how relevant the result is
+    pub fn relevance(&self) -> f32 {
+        self.rerank_score.unwrap_or(self.score)
+    }
 }
 
-impl Proximity<EmbeddedSentence> for EmbeddedSentence {
-    type Distance = f32;
+pub fn parse_date_arg(date: &str) -> Result<i64, String> {
+    frontmatter::parse_date(date).ok_or(format!("Invalid date '{}', expected YYYY-MM-DD", date))
+}
+
+/// Narrows a search down before looking for the nearest neighbors
+#[derive(Args, Debug, Clone, Default)]
+pub struct SearchFilter {
+    /// Only search paths starting with this prefix or matching this glob, absolute or
+    /// relative to the note directory
+    #[arg(long = "path")]
+    pub paths: Vec<String>,
+
+    /// Paths to leave out, only set through the query language
+    #[arg(skip)]
+    pub exclude_paths: Vec<String>,
+
+    /// Only search notes with this tag in their frontmatter
+    #[arg(short, long = "tag")]
+    pub tags: Vec<String>,
+
+    /// Tags to leave out, only set through the query language
+    #[arg(skip)]
+    pub exclude_tags: Vec<String>,
+
+    /// Only search files with this extension
+    #[arg(long = "ext")]
+    pub extensions: Vec<String>,
+
+    /// Only return code blocks in this language
+    #[arg(short, long)]
+    pub lang: Option<String>,
 
-    fn distance(&self, other: &Self) -> Self::Distance {
-        cosine_distance(&self.embedding, &other.embedding)
+    /// Only search files modified on or after this date (YYYY-MM-DD)
+    #[arg(long, value_parser = parse_date_arg)]
+    pub modified_after: Option<i64>,
+
+    /// Only search files modified before this date (YYYY-MM-DD)
+    #[arg(long, value_parser = parse_date_arg)]
+    pub modified_before: Option<i64>,
+
+    /// Only search notes created on or after this date (YYYY-MM-DD)
+    #[arg(long, value_parser = parse_date_arg)]
+    pub created_after: Option<i64>,
+
+    /// Only search notes created before this date (YYYY-MM-DD)
+    #[arg(long, value_parser = parse_date_arg)]
+    pub created_before: Option<i64>,
+}
+
+/// Path prefixes and globs, matched against absolute paths and paths relative to their root
+struct PathPatterns<'a> {
+    prefixes: Vec<&'a str>,
+    globs: GlobSet,
+}
+
+impl<'a> PathPatterns<'a> {
+    fn new(patterns: &'a [String]) -> Result<Self> {
+        let mut prefixes = vec![];
+        let mut globs = GlobSetBuilder::new();
+        for pattern in patterns {
+            if pattern.contains(['*', '?', '[', '{']) {
+                globs.add(Glob::new(pattern)?);
+            } else {
+                prefixes.push(pattern.as_str());
+            }
+        }
+        Ok(Self {
+            prefixes,
+            globs: globs.build()?,
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.prefixes.is_empty() && self.globs.is_empty()
     }
+
+    fn is_match(&self, path: &Path, relative: &Path) -> bool {
+        let (path_str, relative_str) = (path.to_string_lossy(), relative.to_string_lossy());
+        self.globs.is_match(path)
+            || self.globs.is_match(relative)
+            || self
+                .prefixes
+                .iter()
+                .any(|p| path_str.starts_with(p) || relative_str.starts_with(p))
+    }
+}
+
+/// A path relative to the most specific root containing it, or its file name when no
+/// root does, like `MindmapConfig::get_root`
+fn relative_to_root<'a>(path: &'a Path, roots: &[RootConfig]) -> &'a Path {
+    roots
+        .iter()
+        .filter_map(|root| path.strip_prefix(&root.path).ok())
+        .min_by_key(|relative| relative.components().count())
+        .or_else(|| path.file_name().map(Path::new))
+        .unwrap_or(path)
+}
+
+impl SearchFilter {
+    pub fn is_empty(&self) -> bool {
+        self.file_filters_empty() && self.lang.is_none()
+    }
+
+    fn file_filters_empty(&self) -> bool {
+        self.paths.is_empty()
+            && self.exclude_paths.is_empty()
+            && self.tags.is_empty()
+            && self.exclude_tags.is_empty()
+            && self.extensions.is_empty()
+            && self.modified_after.is_none()
+            && self.modified_before.is_none()
+            && self.created_after.is_none()
+            && self.created_before.is_none()
+    }
+
+    /// The rowids of every indexed block that passes the filter
+    pub fn allowed_ids(&self, config: &MindmapConfig) -> Result<HashSet<i64>> {
+        if self.file_filters_empty() {
+            let ids = database::get_ids_by_path(self.lang.as_deref(), config)?;
+            return Ok(ids.into_values().flatten().collect());
+        }
+
+        let ids = database::get_filtered_ids_by_path(self, config)?;
+        let paths = PathPatterns::new(&self.paths)?;
+        let excluded_paths = PathPatterns::new(&self.exclude_paths)?;
+        if paths.is_empty() && excluded_paths.is_empty() {
+            return Ok(ids.into_values().flatten().collect());
+        }
+
+        let roots = config.get_roots();
+        let allowed = ids
+            .into_iter()
+            .filter(|(path, _)| {
+                let relative = relative_to_root(path, &roots);
+                (paths.is_empty() || paths.is_match(path, relative))
+                    && !excluded_paths.is_match(path, relative)
+            })
+            .flat_map(|(_, ids)| ids)
+            .collect();
+        Ok(allowed)
+    }
+}
+
+/// Per query settings, falling back to the config when not set
+#[derive(Args, Debug, Clone, Default)]
+pub struct SearchOptions {
+    #[command(flatten)]
+    pub filter: SearchFilter,
+
+    /// Phrases every result has to contain
+    #[arg(skip)]
+    pub phrases: Vec<String>,
+
+    /// Words and phrases no result may contain
+    #[arg(skip)]
+    pub excluded: Vec<String>,
+
+    /// Phrases embedded apart from the query, only set through the query language
+    #[arg(skip)]
+    pub parts: Vec<QueryPart>,
+
+    /// Only return results scoring at least this much, overrides `min_score`
+    #[arg(short, long)]
+    pub min_score: Option<f32>,
+
+    /// Cut results off where the score drops by more than this, overrides `score_drop`
+    #[arg(long)]
+    pub score_drop: Option<f32>,
+
+    /// Merge adjacent blocks of a note, or return the best notes with their best passages
+    #[arg(value_enum, short, long = "group", default_value = "none")]
+    pub grouping: Grouping,
+
+    /// How many passages each note shows with `--group files`
+    #[arg(long)]
+    pub passages: Option<usize>,
+
+    /// Re-rank for diversity with MMR, from 0 (only diversity) to 1 (only relevance)
+    #[arg(long = "mmr", value_name = "LAMBDA")]
+    pub mmr_lambda: Option<f32>,
+
+    /// Re-score the best results with the cross-encoder, overrides `reranker.enabled`
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    pub rerank: Option<bool>,
+}
+
+/// The text of a block, reading every file only once
+fn block_text<'a>(
+    contents: &'a mut HashMap<PathBuf, Option<String>>,
+    path: &Path,
+    start: usize,
+    end: usize,
+) -> Option<&'a str> {
+    contents
+        .entry(path.to_path_buf())
+        .or_insert_with(|| fs::read_to_string(path).ok())
+        .as_deref()
+        .and_then(|c| c.get(start..end))
+}
+
+/// Scores a block against every part of a query: the similarities to the parts weighted,
+/// summed and divided by the total positive weight, so a single part scores like a plain query
+fn score_parts(embedding: &[f32], parts: &[(QueryPart, Embedding)]) -> (f32, Vec<PartScore>) {
+    let norm = embeddings::dot(embedding, embedding)
+        .sqrt()
+        .max(f32::EPSILON);
+    let total: f32 = parts.iter().map(|(p, _)| p.weight.max(0.0)).sum();
+    let scores: Vec<PartScore> = parts
+        .iter()
+        .map(|(part, part_embedding)| {
+            let similarity = embeddings::dot(embedding, part_embedding) / norm;
+            PartScore {
+                text: part.text.clone(),
+                weight: part.weight,
+                similarity,
+                contribution: part.weight * similarity / total.max(f32::EPSILON),
+            }
+        })
+        .collect();
+    (scores.iter().map(|s| s.contribution).sum(), scores)
 }
 
-impl Proximity<EmbeddedSentence> for Vec<f32> {
-    type Distance = f32;
+/// Finds `needle` as whole words
+fn contains_words(text: &str, needle: &str) -> bool {
+    let is_boundary = |c: Option<char>| c.is_none_or(|c| !c.is_alphanumeric());
+    text.match_indices(needle).any(|(i, _)| {
+        is_boundary(text[..i].chars().next_back())
+            && is_boundary(text[i + needle.len()..].chars().next())
+    })
+}
 
-    fn distance(&self, other: &EmbeddedSentence) -> Self::Distance {
-        cosine_distance(&self, &other.embedding)
+impl SearchOptions {
+    fn is_lexical(&self) -> bool {
+        !self.phrases.is_empty() || !self.excluded.is_empty()
+    }
+
+    /// Whether a block contains every phrase and none of the excluded words, ignoring case
+    fn matches_text(&self, text: &str) -> bool {
+        let text = text.to_lowercase();
+        self.phrases
+            .iter()
+            .all(|p| contains_words(&text, &p.to_lowercase()))
+            && !self
+                .excluded
+                .iter()
+                .any(|e| contains_words(&text, &e.to_lowercase()))
     }
 }
 
-pub struct EmbeddingTree<'a> {
-    tree: VpTree<EmbeddedSentence>,
-    model: Model,
+pub struct EmbeddingIndex<'a> {
+    index: VectorIndex,
+    /// Loaded by the first query that has to be encoded
+    model: OnceCell<Model>,
+    /// Loaded by the first query re-ranking its results
+    reranker: OnceCell<CrossEncoder>,
     config: &'a MindmapConfig,
 }
 
-impl<'a> EmbeddingTree<'a> {
-    pub fn new(corpus: Vec<EmbeddedSentence>, model: Model, config: &'a MindmapConfig) -> Self {
+impl<'a> EmbeddingIndex<'a> {
+    pub fn new(index: VectorIndex, config: &'a MindmapConfig) -> Self {
         Self {
-            tree: VpTree::balanced(corpus),
-            model,
+            index,
+            model: OnceCell::new(),
+            reranker: OnceCell::new(),
             config,
         }
     }
 
-    pub fn rebuild(&mut self, corpus: Vec<EmbeddedSentence>) {
-        self.tree = VpTree::balanced(corpus);
+    pub fn model(&self) -> Result<&Model> {
+        if self.model.get().is_none() {
+            let _ = self.model.set(Model::new(self.config)?);
+        }
+        Ok(self.model.get().expect("Model should be loaded"))
     }
 
-    pub fn search(&self, query: &str) -> Result<Vec<SearchResult>> {
-        let emb = self.model.encode(query)?;
-        let num_resuls = self.config.num_results;
+    pub fn reranker(&self) -> Result<&CrossEncoder> {
+        if self.reranker.get().is_none() {
+            log::info!("Loading reranker: {}", self.config.reranker.model);
+            let reranker = CrossEncoder::new(&self.config.reranker)?;
+            let _ = self.reranker.set(reranker);
+        }
+        Ok(self.reranker.get().expect("Reranker should be loaded"))
+    }
 
-        let results = self
-            .tree
-            .k_nearest(&emb, num_resuls)
+    /// Re-scores the best candidates with the cross-encoder, best first, and drops the
+    /// rest so MMR and grouping never weigh cross-encoder scores against cosine ones
+    fn rerank(
+        &self,
+        query: &str,
+        candidates: &mut Vec<(SearchResult, Embedding)>,
+        contents: &mut HashMap<PathBuf, Option<String>>,
+    ) -> Result<()> {
+        candidates.truncate(self.config.reranker.candidates);
+        let texts: Vec<String> = candidates
             .iter()
-            .map(|x| SearchResult {
-                path: x.item.path.clone(),
-                start_line_no: x.item.start_line_no,
-                end_line_no: x.item.end_line_no,
-                distance: x.distance.value(),
+            .map(|(r, _)| {
+                let text = block_text(contents, &r.path, r.start_offset, r.end_offset);
+                text.unwrap_or_default().to_string()
             })
             .collect();
+        let passages: Vec<&str> = texts.iter().map(String::as_str).collect();
+
+        let scores = self.reranker()?.score(query, &passages)?;
+        for ((result, _), score) in candidates.iter_mut().zip(scores) {
+            result.rerank_score = Some(score);
+        }
+        candidates.sort_by(|(a, _), (b, _)| b.relevance().total_cmp(&a.relevance()));
+        Ok(())
+    }
 
+    /// Picks up the changes other processes saved to the index
+    pub fn reload(&mut self) -> Result<()> {
+        self.index = VectorIndex::open(self.config)?;
+        Ok(())
+    }
+
+    pub fn insert(&mut self, rows: &[(i64, Embedding)]) -> Result<()> {
+        for (id, embedding) in rows {
+            self.index.insert(*id, embedding)?;
+        }
+        Ok(())
+    }
+
+    pub fn remove(&mut self, ids: &[i64]) -> Result<()> {
+        for id in ids {
+            self.index.remove(*id)?;
+        }
+        Ok(())
+    }
+
+    pub fn search(&self, query: &str, opts: &SearchOptions) -> Result<Vec<SearchResult>> {
+        if !opts.parts.is_empty() {
+            return self.search_parts(query, opts);
+        }
+        let emb = self.model()?.encode(query)?;
+        self.search_embedding(&emb, Some(query), &[], &HashSet::new(), opts)
+    }
+
+    /// Searches for the query along with its `+parts` and `~parts`, each embedded on its own
+    fn search_parts(&self, query: &str, opts: &SearchOptions) -> Result<Vec<SearchResult>> {
+        let mut parts = vec![];
+        if !query.trim().is_empty() {
+            parts.push(QueryPart {
+                text: query.to_string(),
+                weight: 1.0,
+            });
+        }
+        parts.extend(opts.parts.iter().cloned());
+        let texts: Vec<&str> = parts.iter().map(|p| p.text.as_str()).collect();
+        let embedded = self.model()?.encode_many(texts)?;
+        let parts: Vec<(QueryPart, Embedding)> = parts
+            .into_iter()
+            .zip(embedded.into_iter().map(embeddings::normalize))
+            .collect();
+
+        // Blocks closest to the weighted sum of the parts are the ones with the best
+        // weighted sum of similarities, so the index can still find the candidates
+        let mut combined = vec![0.0; parts[0].1.len()];
+        for (part, embedding) in &parts {
+            combined
+                .iter_mut()
+                .zip(embedding)
+                .for_each(|(c, e)| *c += part.weight * e);
+        }
+        let combined = embeddings::normalize(combined);
+
+        // The cross-encoder can't tell what to avoid, so it only reads the positive parts
+        let positive: Vec<&str> = parts
+            .iter()
+            .filter(|(p, _)| p.weight > 0.0)
+            .map(|(p, _)| p.text.as_str())
+            .collect();
+        let positive = positive.join(" ");
+        self.search_embedding(&combined, Some(&positive), &parts, &HashSet::new(), opts)
+    }
+
+    /// The blocks closest to a note or some of its blocks, leaving those out. The
+    /// embeddings come from the database, so nothing gets encoded.
+    pub fn similar(
+        &self,
+        target: &SimilarTarget,
+        opts: &SearchOptions,
+    ) -> Result<Vec<SearchResult>> {
+        let (embedding, excluded) = similar::source(target, self.config)?;
+        self.search_embedding(&embedding, None, &[], &excluded, opts)
+    }
+
+    /// Finds the blocks closest to an embedding, other than the `excluded` ones. Results are
+    /// only re-ranked given the `query` the embedding stands for, and scored against the
+    /// `parts` of a multi-part query when given.
+    pub fn search_embedding(
+        &self,
+        emb: &[f32],
+        query: Option<&str>,
+        parts: &[(QueryPart, Embedding)],
+        excluded: &HashSet<i64>,
+        opts: &SearchOptions,
+    ) -> Result<Vec<SearchResult>> {
+        let num_resuls = self.config.num_results;
+        let min_score = opts.min_score.unwrap_or(self.config.min_score);
+        let mmr_lambda = opts.mmr_lambda.or(self.config.mmr_lambda);
+        if mmr_lambda.is_some_and(|l| !(0.0..=1.0).contains(&l)) {
+            bail!("MMR lambda must be between 0 and 1");
+        }
+        let rerank = query.filter(|_| opts.rerank.unwrap_or(self.config.reranker.enabled));
+
+        // Filtering happens inside the graph search, so it still finds enough results
+        let allowed = if opts.filter.is_empty() {
+            None
+        } else {
+            Some(opts.filter.allowed_ids(self.config)?)
+        };
+        // Words can only be checked on the text, so look further for enough results to survive
+        let mut num_candidates = num_resuls;
+        if opts.is_lexical() {
+            num_candidates *= LEXICAL_OVERSAMPLING;
+        }
+        if opts.grouping != Grouping::None {
+            num_candidates *= GROUPING_OVERSAMPLING;
+        }
+        if mmr_lambda.is_some() {
+            num_candidates *= MMR_OVERSAMPLING;
+        }
+        num_candidates = num_candidates.min(MAX_CANDIDATES.max(num_resuls));
+        if rerank.is_some() {
+            num_candidates = num_candidates.max(self.config.reranker.candidates);
+        }
+        num_candidates += excluded.len();
+        let mut neighbors = self.index.search(emb, num_candidates, allowed.as_ref())?;
+        neighbors.retain(|(id, _)| !excluded.contains(id));
+        let ids: Vec<i64> = neighbors.iter().map(|(id, _)| *id).collect();
+        let mut sentences = database::get_by_ids(&ids, self.config)?;
+
+        let mut contents: HashMap<PathBuf, Option<String>> = HashMap::new();
+        let mut candidates: Vec<(SearchResult, Embedding)> = neighbors
+            .into_iter()
+            .filter_map(|(id, distance)| Some((sentences.remove(&id)?, distance)))
+            .filter(|(item, _)| {
+                if !opts.is_lexical() {
+                    return true;
+                }
+                block_text(
+                    &mut contents,
+                    &item.path,
+                    item.start_offset,
+                    item.end_offset,
+                )
+                .is_some_and(|text| opts.matches_text(text))
+            })
+            .map(|(item, distance)| {
+                let (score, part_scores) = match parts.is_empty() {
+                    true => (1.0 - distance, vec![]),
+                    false => score_parts(&item.embedding, parts),
+                };
+                let result = SearchResult {
+                    path: item.path,
+                    collection: item.collection,
+                    start_line_no: item.start_line_no,
+                    start_col: item.start_col,
+                    start_offset: item.start_offset,
+                    end_line_no: item.end_line_no,
+                    end_col: item.end_col,
+                    end_offset: item.end_offset,
+                    breadcrumb: item.breadcrumb,
+                    lang: item.lang,
+                    score: score.clamp(0.0, 1.0),
+                    rerank_score: None,
+                    parts: part_scores,
+                };
+                (result, item.embedding)
+            })
+            .filter(|(r, _)| r.score >= min_score)
+            .collect();
+        if !parts.is_empty() {
+            // The index only approximates the order of the scores
+            candidates.sort_by(|(a, _), (b, _)| b.score.total_cmp(&a.score));
+        }
+
+        if let Some(max_drop) = opts.score_drop.or(self.config.score_drop) {
+            cut_at_drop(&mut candidates, max_drop);
+        }
+        if let Some(query) = rerank {
+            self.rerank(query, &mut candidates, &mut contents)?;
+        }
+        let results = match mmr_lambda {
+            // Grouping needs more blocks than results, as it merges some of them
+            Some(lambda) => {
+                let picks = match opts.grouping {
+                    Grouping::None => num_resuls,
+                    _ => num_resuls * GROUPING_OVERSAMPLING,
+                };
+                rerank::mmr(candidates, lambda, picks)
+            }
+            None => candidates.into_iter().map(|(r, _)| r).collect(),
+        };
+        let mut results = match opts.grouping {
+            Grouping::None => results,
+            Grouping::Merge => grouping::merge_adjacent(results),
+            Grouping::Files => {
+                // Up to `num_results` notes rather than blocks
+                let passages = opts.passages.unwrap_or(grouping::DEFAULT_PASSAGES);
+                let merged = grouping::merge_adjacent(results);
+                return Ok(grouping::top_files(merged, num_resuls, passages));
+            }
+        };
+        results.truncate(num_resuls);
         Ok(results)
     }
 }
 
-pub fn search(query: &str, config: &MindmapConfig, format: OutputFormat) -> Result<()> {
-    let corpus = database::get_all(config)?;
-    let model = Model::new(config).unwrap();
+/// Drops every result after the first gap between consecutive scores larger than `max_drop`
+fn cut_at_drop<T>(results: &mut Vec<(SearchResult, T)>, max_drop: f32) {
+    let cut = results
+        .windows(2)
+        .position(|pair| pair[0].0.score - pair[1].0.score > max_drop);
+    if let Some(i) = cut {
+        results.truncate(i + 1);
+    }
+}
 
-    let tree = EmbeddingTree::new(corpus, model, config);
-    let results = tree.search(query)?;
+pub fn search(
+    query: &str,
+    opts: &SearchOptions,
+    config: &MindmapConfig,
+    format: OutputFormat,
+) -> Result<()> {
+    // Memory-mapped, so a single query doesn't load the whole index
+    let index = VectorIndex::view(config)?;
+    let index = EmbeddingIndex::new(index, config);
+    let results = index.search(query, opts)?;
 
     // Format response
-    let formatted = formatter::format(&results, format);
+    let formatted = formatter::format(&results, opts.grouping, format);
     println!("{}", formatted);
     Ok(())
 }
@@ -1,26 +1,62 @@
 use crate::{
     config::MindmapConfig,
     database,
-    embeddings::Model,
     formatter::{self, OutputFormat},
-    search::EmbeddingTree,
+    index::VectorIndex,
+    links,
+    map::{self, ColorBy, MapOptions, Projection},
+    query,
+    search::{self, EmbeddingIndex, SearchFilter, SearchOptions},
+    similar::SimilarTarget,
     utils,
 };
 use anyhow::Result;
 use colored::Colorize;
 use std::{
     collections::HashMap,
+    fmt::{Display, Formatter},
     io::{Read, Write},
     net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    str::FromStr,
 };
-use url::Url;
+use url::{form_urlencoded, Url};
 
-pub fn notify_rebuild(config: &MindmapConfig) -> Result<()> {
+/// What happened to a file, as sent to the server after the database was updated
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FileOp {
+    /// Created or modified
+    Update,
+    Delete,
+}
+
+impl FromStr for FileOp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "update" => Ok(FileOp::Update),
+            "delete" => Ok(FileOp::Delete),
+            _ => Err("Invalid file operation".to_string()),
+        }
+    }
+}
+
+impl Display for FileOp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FileOp::Update => write!(f, "update"),
+            FileOp::Delete => write!(f, "delete"),
+        }
+    }
+}
+
+fn send_request(path: &str, config: &MindmapConfig) -> Result<()> {
     let host = &config.server.host;
     let port = config.server.port;
     let addr = format!("{}:{}", host, port);
 
-    let get = format!("GET /rebuild HTTP/1.1\r\nHost: {}\r\n\r\n", addr);
+    let get = format!("GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", path, addr);
 
     let mut sock = TcpStream::connect(addr)?;
     sock.write_all(get.as_bytes())?;
@@ -28,9 +64,27 @@ pub fn notify_rebuild(config: &MindmapConfig) -> Result<()> {
     Ok(())
 }
 
+/// Makes the server reload its whole index, e.g. after reindexing many files
+pub fn notify_rebuild(config: &MindmapConfig) -> Result<()> {
+    send_request("/rebuild", config)
+}
+
+/// Lets the server update the embeddings of a single file in place
+pub fn notify_change(file: &Path, op: FileOp, config: &MindmapConfig) -> Result<()> {
+    let query = form_urlencoded::Serializer::new(String::new())
+        .append_pair("path", &file.to_string_lossy())
+        .append_pair("op", &op.to_string())
+        .finish();
+    send_request(&format!("/change?{}", query), config)
+}
+
 enum RequestType {
-    Search(String, OutputFormat),
+    Search(String, Box<SearchOptions>, OutputFormat),
+    Similar(SimilarTarget, Box<SearchOptions>, OutputFormat),
+    Change(PathBuf, FileOp),
     Rebuild,
+    Graph,
+    Map(MapOptions),
 }
 
 pub struct Server;
@@ -64,39 +118,213 @@ impl Server {
         }
 
         let parsed_url = Url::parse(&format!("http://localhost{}", path))?;
+        if parsed_url.path() == "/graph" {
+            return Ok(RequestType::Graph);
+        }
+
         let hash_query: HashMap<_, _> = parsed_url.query_pairs().into_owned().collect();
-        let query = hash_query
-            .get("q")
-            .ok_or(anyhow::anyhow!("No query in request"))?;
+        if parsed_url.path() == "/map" {
+            return Self::parse_map(&hash_query).map(RequestType::Map);
+        }
+        if parsed_url.path() == "/change" {
+            let path = hash_query
+                .get("path")
+                .ok_or(anyhow::anyhow!("No path in request"))?;
+            let op = hash_query
+                .get("op")
+                .ok_or(anyhow::anyhow!("No operation in request"))?
+                .parse()
+                .map_err(|e: String| anyhow::anyhow!(e))?;
+            return Ok(RequestType::Change(PathBuf::from(path), op));
+        }
 
         let output_format = hash_query
             .get("format")
             .map(|f| f.parse().unwrap_or(OutputFormat::Raw))
             .unwrap_or(OutputFormat::Raw);
+        let mut opts = Self::parse_options(&parsed_url, &hash_query)?;
 
-        Ok(RequestType::Search(query.to_string(), output_format))
+        if parsed_url.path() == "/similar" {
+            let target = hash_query
+                .get("file")
+                .ok_or(anyhow::anyhow!("No file in request"))?
+                .parse()
+                .map_err(|e: String| anyhow::anyhow!(e))?;
+            return Ok(RequestType::Similar(target, Box::new(opts), output_format));
+        }
+
+        let query = hash_query
+            .get("q")
+            .ok_or(anyhow::anyhow!("No query in request"))?;
+        let parsed = query::parse(query)?;
+        let text = parsed.text.clone();
+        parsed.merge_into(&mut opts);
+
+        Ok(RequestType::Search(text, Box::new(opts), output_format))
     }
 
-    fn handle_query(query: &String, format: OutputFormat, tree: &EmbeddingTree) -> Result<String> {
+    fn parse_map(hash_query: &HashMap<String, String>) -> Result<MapOptions> {
+        let parse_err = |e: String| anyhow::anyhow!(e);
+        Ok(MapOptions {
+            blocks: hash_query
+                .get("blocks")
+                .map(|b| b.parse())
+                .transpose()?
+                .unwrap_or(false),
+            color_by: hash_query
+                .get("color")
+                .map(|c| c.parse().map_err(parse_err))
+                .transpose()?
+                .unwrap_or_default(),
+            projection: hash_query
+                .get("projection")
+                .map(|p| p.parse().map_err(parse_err))
+                .transpose()?
+                .unwrap_or_default(),
+            // Not taken from the request, a link there could run anything in the page
+            ..Default::default()
+        })
+    }
+
+    /// The search settings shared by queries and `/similar`
+    fn parse_options(
+        parsed_url: &Url,
+        hash_query: &HashMap<String, String>,
+    ) -> Result<SearchOptions> {
+        // Filters that can be given more than once, e.g. `tag=a&tag=b`
+        let all = |key: &str| -> Vec<String> {
+            parsed_url
+                .query_pairs()
+                .filter(|(k, _)| k == key)
+                .map(|(_, v)| v.into_owned())
+                .collect()
+        };
+        let date = |key: &str| -> Result<Option<i64>> {
+            let date = hash_query.get(key).map(|d| search::parse_date_arg(d));
+            date.transpose().map_err(|e| anyhow::anyhow!(e))
+        };
+        let filter = SearchFilter {
+            paths: all("path"),
+            tags: all("tag"),
+            extensions: all("ext"),
+            lang: hash_query.get("lang").cloned(),
+            modified_after: date("modified_after")?,
+            modified_before: date("modified_before")?,
+            created_after: date("created_after")?,
+            created_before: date("created_before")?,
+            ..Default::default()
+        };
+
+        let opts = SearchOptions {
+            filter,
+            min_score: hash_query.get("min_score").map(|s| s.parse()).transpose()?,
+            score_drop: hash_query
+                .get("score_drop")
+                .map(|s| s.parse())
+                .transpose()?,
+            grouping: hash_query
+                .get("group")
+                .map(|g| g.parse().map_err(|e: String| anyhow::anyhow!(e)))
+                .transpose()?
+                .unwrap_or_default(),
+            passages: hash_query.get("passages").map(|p| p.parse()).transpose()?,
+            mmr_lambda: hash_query.get("mmr").map(|l| l.parse()).transpose()?,
+            rerank: hash_query.get("rerank").map(|r| r.parse()).transpose()?,
+            ..Default::default()
+        };
+        Ok(opts)
+    }
+
+    fn handle_query(
+        query: &String,
+        opts: &SearchOptions,
+        format: OutputFormat,
+        index: &EmbeddingIndex,
+    ) -> Result<String> {
         println!("{} '{}' ({})", "Querying for".blue(), query, format);
-        let results = tree.search(&query.to_string())?;
-        let formatted = formatter::format(&results, format);
+        let results = index.search(&query.to_string(), opts)?;
+        let formatted = formatter::format(&results, opts.grouping, format);
         Ok(formatted)
     }
 
-    fn handle_rebuild(tree: &mut EmbeddingTree, config: &MindmapConfig) -> Result<String> {
-        let corpus = database::get_all(config)?;
-        println!("{}", "Rebuilding...".blue());
-        tree.rebuild(corpus);
+    fn handle_similar(
+        target: &SimilarTarget,
+        opts: &SearchOptions,
+        format: OutputFormat,
+        index: &EmbeddingIndex,
+    ) -> Result<String> {
+        println!(
+            "{} {} ({})",
+            "Finding notes similar to".blue(),
+            target,
+            format
+        );
+        let results = index.similar(target, opts)?;
+        Ok(formatter::format(&results, opts.grouping, format))
+    }
+
+    fn handle_rebuild(
+        index: &mut EmbeddingIndex,
+        files: &mut HashMap<PathBuf, Vec<i64>>,
+        config: &MindmapConfig,
+    ) -> Result<String> {
+        println!("{}", "Reloading index...".blue());
+        index.reload()?;
+        *files = database::get_ids_by_path(None, config)?;
         Ok("Rebuilt".to_string())
     }
 
-    fn send_response(code: u16, body: &str, stream: &mut TcpStream) -> Result<()> {
+    /// Draws the map, or sends the one drawn since the last change, as t-SNE keeps the
+    /// server busy for a while
+    fn handle_map(
+        opts: &MapOptions,
+        maps: &mut HashMap<(bool, ColorBy, Projection), String>,
+        config: &MindmapConfig,
+    ) -> Result<String> {
+        let key = (opts.blocks, opts.color_by, opts.projection);
+        if let Some(html) = maps.get(&key) {
+            return Ok(html.clone());
+        }
+        println!("{} ({})", "Drawing map".blue(), opts.projection);
+        let html = map::map(opts, config)?;
+        maps.insert(key, html.clone());
+        Ok(html)
+    }
+
+    /// Swaps the embeddings of a single file in the index for the ones now in the database
+    fn handle_change(
+        path: PathBuf,
+        op: FileOp,
+        index: &mut EmbeddingIndex,
+        files: &mut HashMap<PathBuf, Vec<i64>>,
+        config: &MindmapConfig,
+    ) -> Result<String> {
+        println!("{} {:?} ({})", "Updating".blue(), path, op);
+        let removed = files.remove(&path).unwrap_or_default();
+        index.remove(&removed)?;
+
+        if op == FileOp::Update {
+            let rows = database::get_file_embeddings(&path, config)?;
+            files.insert(path, rows.iter().map(|(id, _)| *id).collect());
+            index.insert(&rows)?;
+        }
+        Ok("Updated".to_string())
+    }
+
+    fn send_response(
+        code: u16,
+        body: &str,
+        content_type: Option<&str>,
+        stream: &mut TcpStream,
+    ) -> Result<()> {
         let length = body.len();
 
         // CORS stuff
-        let headers =
+        let mut headers =
             "Access-Control-Allow-Origin: *\r\nAccess-Control-Allow-Methods: GET\r\n".to_string();
+        if let Some(content_type) = content_type {
+            headers.push_str(&format!("Content-Type: {}\r\n", content_type));
+        }
 
         let response = format!(
             "HTTP/1.1 {}\r\n{}Content-Length: {}\r\n\r\n{}",
@@ -118,9 +346,16 @@ impl Server {
         // Load model
         log::info!("Loading model: {:?}", config.model);
         println!("{}: {:?}", "Loading model".blue(), &config.model);
-        let model = Model::new(config)?;
-        let corpus = database::get_all(config)?;
-        let mut tree = EmbeddingTree::new(corpus, model, config);
+        let index = VectorIndex::open(config)?;
+        let mut index = EmbeddingIndex::new(index, config);
+        index.model()?;
+        if config.reranker.enabled {
+            println!("{}: {}", "Loading reranker".blue(), config.reranker.model);
+            index.reranker()?;
+        }
+        let mut files = database::get_ids_by_path(None, config)?;
+        // Maps drawn since the last change
+        let mut maps = HashMap::new();
 
         // Start app
         log::info!("Starting server at {}", addr);
@@ -133,19 +368,38 @@ impl Server {
             // Parse stream
             let stream_type = Self::parse_request(&mut stream);
             if let Err(err) = stream_type {
-                Self::send_response(400, &err.to_string(), &mut stream)?;
+                Self::send_response(400, &err.to_string(), None, &mut stream)?;
                 continue;
             }
 
-            let res = match stream_type.unwrap() {
-                RequestType::Search(query, format) => Self::handle_query(&query, format, &tree),
-                RequestType::Rebuild => Self::handle_rebuild(&mut tree, config),
+            let request = stream_type.unwrap();
+            let content_type = match request {
+                RequestType::Map(_) => Some("text/html; charset=utf-8"),
+                _ => None,
+            };
+            let res = match request {
+                RequestType::Search(query, opts, format) => {
+                    Self::handle_query(&query, &opts, format, &index)
+                }
+                RequestType::Similar(target, opts, format) => {
+                    Self::handle_similar(&target, &opts, format, &index)
+                }
+                RequestType::Change(path, op) => {
+                    maps.clear();
+                    Self::handle_change(path, op, &mut index, &mut files, config)
+                }
+                RequestType::Rebuild => {
+                    maps.clear();
+                    Self::handle_rebuild(&mut index, &mut files, config)
+                }
+                RequestType::Graph => links::graph(config),
+                RequestType::Map(opts) => Self::handle_map(&opts, &mut maps, config),
             };
 
             // Send response
             match res {
-                Ok(msg) => Self::send_response(200, &msg, &mut stream)?,
-                Err(e) => Self::send_response(500, &e.to_string(), &mut stream)?,
+                Ok(msg) => Self::send_response(200, &msg, content_type, &mut stream)?,
+                Err(e) => Self::send_response(500, &e.to_string(), None, &mut stream)?,
             };
         }
 
@@ -1,4 +1,7 @@
-use crate::config::{MindmapConfig, ModelConfig, ServerConfig, WatcherConfig};
+use crate::chunking::{ChunkingStrategy, CodeBlockMode};
+use crate::config::{
+    ChunkingConfig, DataDir, MindmapConfig, ModelConfig, ServerConfig, WatcherConfig,
+};
 use crate::embeddings::ModelType;
 use anyhow::Result;
 use colored::Colorize;
@@ -38,8 +41,9 @@ pub fn setup() -> Result<()> {
 
     let def_config = MindmapConfig::default();
 
+    let def_data_dir = def_config.get_roots()[0].path.clone();
     let data_dir: PathBuf = Text::new("Where do you want to write your notes?")
-        .with_default(def_config.data_dir.to_str().unwrap())
+        .with_default(def_data_dir.to_str().unwrap())
         .prompt()?
         .into();
 
@@ -113,16 +117,38 @@ pub fn setup() -> Result<()> {
             .prompt()?
             .into(),
     };
+    let chunking = ChunkingConfig {
+        breadcrumbs: Confirm::new("Do you want to include heading breadcrumbs in embeddings?")
+            .with_default(def_config.chunking.breadcrumbs)
+            .prompt()?,
+        strategy: Select::new(
+            "How do you want to split your notes?",
+            ChunkingStrategy::all(),
+        )
+        .prompt()?,
+        directories: def_config.chunking.directories,
+        nested: def_config.chunking.nested,
+        code_blocks: Select::new(
+            "How do you want to handle code blocks?",
+            CodeBlockMode::all(),
+        )
+        .prompt()?,
+    };
 
     let config = MindmapConfig {
-        data_dir,
+        data_dir: DataDir::Single(data_dir),
         db_path,
         log_path,
         min_score,
+        score_drop: def_config.score_drop,
+        mmr_lambda: def_config.mmr_lambda,
         model,
+        reranker: def_config.reranker,
         num_results,
         server,
         watcher,
+        chunking,
+        ignore: def_config.ignore,
     };
     config.save()?;
 
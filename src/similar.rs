@@ -0,0 +1,153 @@
+use std::{
+    collections::HashSet,
+    fmt::{Display, Formatter},
+    path::{self, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{bail, Result};
+use rust_bert::pipelines::sentence_embeddings::Embedding;
+
+use crate::{
+    config::MindmapConfig,
+    database, embeddings,
+    formatter::{self, OutputFormat},
+    index::VectorIndex,
+    search::{EmbeddingIndex, SearchOptions},
+};
+
+/// A note, or the blocks of a note touching some lines, written `file[:start[:end]]`
+#[derive(Clone, Debug)]
+pub struct SimilarTarget {
+    pub path: PathBuf,
+    /// First and last line, both included
+    pub lines: Option<(usize, usize)>,
+}
+
+impl FromStr for SimilarTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Line numbers come last, so colons earlier on are part of the path
+        let mut path = s;
+        let mut numbers = vec![];
+        while numbers.len() < 2 {
+            let Some((rest, last)) = path.rsplit_once(':') else {
+                break;
+            };
+            let Ok(number) = last.parse::<usize>() else {
+                break;
+            };
+            numbers.insert(0, number);
+            path = rest;
+        }
+
+        let lines = match numbers[..] {
+            [] => None,
+            [line] => Some((line, line)),
+            [start, end] if start <= end => Some((start, end)),
+            _ => return Err(format!("Invalid line range in '{}'", s)),
+        };
+        Ok(Self {
+            path: PathBuf::from(path),
+            lines,
+        })
+    }
+}
+
+impl Display for SimilarTarget {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.lines {
+            Some((start, end)) => write!(f, "{}:{}:{}", self.path.display(), start, end),
+            None => write!(f, "{}", self.path.display()),
+        }
+    }
+}
+
+/// The embedding standing for the target, the mean of its blocks' embeddings, and the
+/// rowids of those blocks
+pub fn source(target: &SimilarTarget, config: &MindmapConfig) -> Result<(Embedding, HashSet<i64>)> {
+    let mut sentences = database::get_file_sentences(&target.path, config)?;
+    if sentences.is_empty() {
+        // Paths are stored absolute
+        let absolute = path::absolute(&target.path)?;
+        sentences = database::get_file_sentences(&absolute, config)?;
+    }
+    if sentences.is_empty() {
+        bail!("{:?} is not indexed", target.path);
+    }
+
+    if let Some((start, end)) = target.lines {
+        sentences.retain(|(_, s)| s.start_line_no <= end && s.end_line_no >= start);
+        if sentences.is_empty() {
+            bail!(
+                "No block of {:?} touches lines {}-{}",
+                target.path,
+                start,
+                end
+            );
+        }
+    }
+
+    let rows: Vec<&Embedding> = sentences.iter().map(|(_, s)| &s.embedding).collect();
+    let mean = embeddings::mean(&rows).expect("Target should have blocks");
+    let ids = sentences.into_iter().map(|(id, _)| id).collect();
+    Ok((mean, ids))
+}
+
+pub fn similar(
+    target: &SimilarTarget,
+    opts: &SearchOptions,
+    config: &MindmapConfig,
+    format: OutputFormat,
+) -> Result<()> {
+    let index = VectorIndex::view(config)?;
+    let index = EmbeddingIndex::new(index, config);
+    let results = index.similar(target, opts)?;
+
+    let formatted = formatter::format(&results, opts.grouping, format);
+    println!("{}", formatted);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(target: &str) -> (PathBuf, Option<(usize, usize)>) {
+        let target: SimilarTarget = target.parse().unwrap();
+        (target.path, target.lines)
+    }
+
+    #[test]
+    fn lines() {
+        assert_eq!(parse("notes/a.md"), (PathBuf::from("notes/a.md"), None));
+        assert_eq!(
+            parse("notes/a.md:3"),
+            (PathBuf::from("notes/a.md"), Some((3, 3)))
+        );
+        assert_eq!(
+            parse("notes/a.md:3:8"),
+            (PathBuf::from("notes/a.md"), Some((3, 8)))
+        );
+        assert!("notes/a.md:8:3".parse::<SimilarTarget>().is_err());
+    }
+
+    #[test]
+    fn colons_in_paths() {
+        assert_eq!(
+            parse(r"C:\notes\a.md"),
+            (PathBuf::from(r"C:\notes\a.md"), None)
+        );
+        assert_eq!(
+            parse(r"C:\notes\a.md:1:2"),
+            (PathBuf::from(r"C:\notes\a.md"), Some((1, 2)))
+        );
+        assert_eq!(
+            parse("meetings/10:30 sync.md:4"),
+            (PathBuf::from("meetings/10:30 sync.md"), Some((4, 4)))
+        );
+        // Only the last two numbers are lines
+        assert_eq!(parse("log:1:2:3"), (PathBuf::from("log:1"), Some((2, 3))));
+    }
+}
@@ -0,0 +1,287 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Result};
+use colored::Colorize;
+use rust_bert::pipelines::sentence_embeddings::Embedding;
+use serde::Serialize;
+
+use crate::{
+    config::MindmapConfig,
+    database,
+    embeddings::{dot, normalize},
+    formatter::OutputFormat,
+    utils::Rng,
+};
+
+/// Lloyd iterations to run at most, clustering usually settles way before
+const MAX_ITERATIONS: usize = 100;
+/// How many keywords label a topic
+const NUM_KEYWORDS: usize = 5;
+/// The first line of every index note, telling it apart from notes that must not be overwritten
+const GENERATED_MARKER: &str = "<!-- Generated by mindmap topics, overwritten on every run -->";
+/// Words too common to tell topics apart
+const STOPWORDS: [&str; 64] = [
+    "the", "and", "for", "are", "but", "not", "you", "all", "any", "can", "had", "her", "was",
+    "one", "our", "out", "has", "have", "him", "his", "how", "its", "may", "new", "now", "old",
+    "see", "two", "way", "who", "did", "get", "let", "she", "too", "use", "this", "that", "with",
+    "from", "they", "will", "what", "when", "your", "which", "their", "there", "been", "were",
+    "than", "then", "them", "into", "some", "would", "could", "should", "about", "also", "just",
+    "http", "https", "www",
+];
+
+#[derive(Debug, Serialize)]
+pub struct TopicNote {
+    pub path: PathBuf,
+    /// Cosine similarity to the center of the topic
+    pub score: f32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Topic {
+    /// The keywords telling this topic apart from the others, most telling first
+    pub keywords: Vec<String>,
+    /// The note closest to the center of the topic
+    pub central: PathBuf,
+    /// Every note of the topic, closest to its center first
+    pub notes: Vec<TopicNote>,
+}
+
+impl Topic {
+    pub fn label(&self) -> String {
+        self.keywords.join(", ")
+    }
+}
+
+/// Picks starting centers far apart from each other, k-means++ style
+fn initial_centers(points: &[Embedding], k: usize) -> Vec<Embedding> {
+    // Seeded, so clustering the same notes always gives the same topics
+    let mut rng = Rng::default();
+    let mut centers = vec![points[0].clone()];
+    let mut distances: Vec<f32> = points.iter().map(|p| 1.0 - dot(p, &points[0])).collect();
+    while centers.len() < k {
+        let total: f32 = distances.iter().map(|d| d.max(0.0)).sum();
+        let mut target = rng.next_f32() * total;
+        let next = distances
+            .iter()
+            .position(|d| {
+                target -= d.max(0.0);
+                target <= 0.0
+            })
+            .unwrap_or(points.len() - 1);
+        centers.push(points[next].clone());
+        for (distance, point) in distances.iter_mut().zip(points) {
+            *distance = distance.min(1.0 - dot(point, &points[next]));
+        }
+    }
+    centers
+}
+
+fn closest(point: &[f32], centers: &[Embedding]) -> usize {
+    (0..centers.len())
+        .max_by(|&a, &b| dot(point, &centers[a]).total_cmp(&dot(point, &centers[b])))
+        .expect("There should be centers")
+}
+
+/// Spherical k-means: clusters normalized points by cosine similarity, returning the
+/// cluster of every point and the center of every cluster
+fn kmeans(points: &[Embedding], k: usize) -> (Vec<usize>, Vec<Embedding>) {
+    let mut centers = initial_centers(points, k);
+    let mut assignments = vec![usize::MAX; points.len()];
+    for _ in 0..MAX_ITERATIONS {
+        let next: Vec<usize> = points.iter().map(|p| closest(p, &centers)).collect();
+        if next == assignments {
+            break;
+        }
+        assignments = next;
+
+        let mut sums = vec![vec![0.0; points[0].len()]; k];
+        for (point, &cluster) in points.iter().zip(&assignments) {
+            sums[cluster]
+                .iter_mut()
+                .zip(point)
+                .for_each(|(s, x)| *s += x);
+        }
+        for (center, sum) in centers.iter_mut().zip(sums) {
+            // A cluster left empty keeps its center
+            if sum.iter().any(|x| *x != 0.0) {
+                *center = normalize(sum);
+            }
+        }
+    }
+    (assignments, centers)
+}
+
+/// The distinct words of a note worth labeling a topic with
+fn words(path: &Path) -> HashSet<String> {
+    let content = fs::read_to_string(path).unwrap_or_default();
+    content
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| w.chars().count() >= 3 && !w.chars().all(|c| c.is_numeric()))
+        .map(str::to_lowercase)
+        .filter(|w| !STOPWORDS.contains(&w.as_str()))
+        .collect()
+}
+
+/// The words found in many notes of the topic but few other notes, as in TF-IDF
+fn keywords(
+    topic: &[usize],
+    words: &[HashSet<String>],
+    notes_with: &HashMap<&str, usize>,
+) -> Vec<String> {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for &note in topic {
+        for word in &words[note] {
+            *counts.entry(word.as_str()).or_default() += 1;
+        }
+    }
+
+    let num_notes = words.len() as f32;
+    let mut scored: Vec<(&str, f32)> = counts
+        .into_iter()
+        // A word only one note of a larger topic uses doesn't describe it
+        .filter(|(_, count)| *count > 1 || topic.len() == 1)
+        .map(|(word, count)| {
+            let idf = (num_notes / notes_with[word] as f32).ln();
+            (word, count as f32 / topic.len() as f32 * idf)
+        })
+        .collect();
+    scored.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(b.0)));
+    scored
+        .into_iter()
+        .take(NUM_KEYWORDS)
+        .map(|(word, _)| word.to_string())
+        .collect()
+}
+
+/// Clusters the notes into `num_topics` topics, around `sqrt(notes / 2)` if not given
+pub fn topics(num_topics: Option<usize>, config: &MindmapConfig) -> Result<Vec<Topic>> {
+    let notes = database::get_note_embeddings(config)?;
+    if notes.is_empty() {
+        bail!("No notes indexed, run `mindmap recompute-all` first");
+    }
+    let k = num_topics
+        .unwrap_or(((notes.len() as f32 / 2.0).sqrt().round() as usize).max(2))
+        .clamp(1, notes.len());
+
+    let (paths, embeddings): (Vec<PathBuf>, Vec<Embedding>) = notes.into_iter().unzip();
+    let points: Vec<Embedding> = embeddings.into_iter().map(normalize).collect();
+    let (assignments, centers) = kmeans(&points, k);
+
+    let words: Vec<HashSet<String>> = paths.iter().map(|p| words(p)).collect();
+    let mut notes_with: HashMap<&str, usize> = HashMap::new();
+    for word in words.iter().flatten() {
+        *notes_with.entry(word.as_str()).or_default() += 1;
+    }
+
+    let mut topics: Vec<Topic> = (0..k)
+        .filter_map(|cluster| {
+            let members: Vec<usize> = (0..points.len())
+                .filter(|&i| assignments[i] == cluster)
+                .collect();
+            let mut notes: Vec<TopicNote> = members
+                .iter()
+                .map(|&i| TopicNote {
+                    path: paths[i].clone(),
+                    score: dot(&points[i], &centers[cluster]),
+                })
+                .collect();
+            notes.sort_by(|a, b| b.score.total_cmp(&a.score));
+            Some(Topic {
+                keywords: keywords(&members, &words, &notes_with),
+                central: notes.first()?.path.clone(),
+                notes,
+            })
+        })
+        .collect();
+    topics.sort_by_key(|t| std::cmp::Reverse(t.notes.len()));
+    Ok(topics)
+}
+
+/// A link to `path` that works from a note in `dir`
+fn link(path: &Path, dir: &Path) -> String {
+    let target = path.strip_prefix(dir).unwrap_or(path);
+    let title = path.file_stem().unwrap_or_default().to_string_lossy();
+    format!("[{}](<{}>)", title, target.display())
+}
+
+/// An index note listing every topic with links to its notes
+pub fn to_markdown(topics: &[Topic], dir: &Path) -> String {
+    let mut markdown = format!("{}\n# Topics\n", GENERATED_MARKER);
+    for topic in topics {
+        markdown.push_str(&format!("\n## {}\n\n", topic.label()));
+        markdown.push_str(&format!("Central note: {}\n\n", link(&topic.central, dir)));
+        for note in &topic.notes {
+            markdown.push_str(&format!("- {}\n", link(&note.path, dir)));
+        }
+    }
+    markdown
+}
+
+/// Writes the index note to `path`. A file that isn't an earlier index note is only
+/// overwritten with `force`.
+pub fn write_index(
+    topics: &[Topic],
+    path: &Path,
+    force: bool,
+    config: &MindmapConfig,
+) -> Result<()> {
+    let generated = fs::read_to_string(path).is_ok_and(|s| s.starts_with(GENERATED_MARKER));
+    if path.exists() && !generated && !force {
+        bail!(
+            "{:?} wasn't written by `mindmap topics`, pass --force to overwrite it",
+            path
+        );
+    }
+
+    let absolute = std::path::absolute(path)?;
+    if config
+        .get_roots()
+        .iter()
+        .any(|root| absolute.starts_with(&root.path))
+    {
+        log::warn!("Topic index {:?} is inside a data root", path);
+        eprintln!(
+            "{}: {:?} is inside your notes, ignore it or it shows up in searches and topics",
+            "Warning".yellow(),
+            path
+        );
+    }
+
+    let dir = path.parent().unwrap_or(Path::new(""));
+    fs::write(path, to_markdown(topics, dir))?;
+    Ok(())
+}
+
+pub fn format_topics(topics: &[Topic], format: OutputFormat) -> String {
+    match format {
+        OutputFormat::Json => serde_json::to_string(topics).unwrap(),
+        // One note per line, a blank line between topics
+        OutputFormat::Raw => topics
+            .iter()
+            .map(|t| {
+                t.notes
+                    .iter()
+                    .map(|n| format!("{}\n", n.path.display()))
+                    .collect::<String>()
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+        OutputFormat::List => topics
+            .iter()
+            .map(|t| {
+                let title = format!("{} ({} notes)", t.label(), t.notes.len());
+                let notes: Vec<String> = t
+                    .notes
+                    .iter()
+                    .map(|n| format!("{} - {:.3}", n.path.display(), n.score))
+                    .collect();
+                format!("{}\n{}", title.blue(), notes.join("\n"))
+            })
+            .collect::<Vec<_>>()
+            .join("\n\n"),
+    }
+}
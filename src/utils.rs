@@ -1,4 +1,4 @@
-use std::{fs::File, path::PathBuf, thread};
+use std::{fs::File, path::Path, thread};
 
 use anyhow::Result;
 use colored::Colorize;
@@ -8,12 +8,24 @@ use signal_hook::{
     iterator::Signals,
 };
 
-pub fn acquire_lock(path: &PathBuf) -> Result<()> {
+fn open_lock(path: &Path) -> Result<File> {
     let file = if !path.exists() {
         File::create(path)?
     } else {
         File::open(path)?
     };
+    Ok(file)
+}
+
+/// Locks a file until the returned handle is dropped, waiting while another process holds it
+pub fn lock_file(path: &Path) -> Result<File> {
+    let file = open_lock(path)?;
+    file.lock_exclusive()?;
+    Ok(file)
+}
+
+pub fn acquire_lock(path: &Path) -> Result<()> {
+    let file = open_lock(path)?;
     let res = file.try_lock_exclusive();
     if res.is_err() {
         println!("Another instance is running, waiting for it to finish...");
@@ -31,3 +43,22 @@ pub fn acquire_lock(path: &PathBuf) -> Result<()> {
 
     Ok(())
 }
+
+/// A small xorshift generator with a fixed seed, for results that don't change from run to run
+pub struct Rng(u64);
+
+impl Default for Rng {
+    fn default() -> Self {
+        Self(0x2545_f491_4f6c_dd1d)
+    }
+}
+
+impl Rng {
+    /// A number in `[0, 1)`
+    pub fn next_f32(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
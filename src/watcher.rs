@@ -1,16 +1,26 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 extern crate fs2;
 
-use crate::{config::MindmapConfig, files, utils};
+use crate::{
+    config::{MindmapConfig, RootConfig},
+    files,
+    filter::PathFilter,
+    index::VectorIndex,
+    utils,
+};
 use anyhow::Result;
 use colored::Colorize;
 use notify::{Config, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use walkdir::WalkDir;
 
 pub struct MindmapWatcher {
     watcher: RecommendedWatcher,
     rx: std::sync::mpsc::Receiver<notify::Result<Event>>,
     config: MindmapConfig,
+    roots: Vec<(RootConfig, PathFilter)>,
+    /// Symlink targets watched on their own, mapped to the path of the link
+    symlinks: Vec<(PathBuf, PathBuf)>,
 }
 
 impl MindmapWatcher {
@@ -18,11 +28,12 @@ impl MindmapWatcher {
         &self,
         kind: notify::event::CreateKind,
         paths: Vec<PathBuf>,
+        index: &mut VectorIndex,
     ) -> Result<()> {
         let path_str = paths.first().expect("Path should exist");
         let path = PathBuf::from(path_str);
         if kind == notify::event::CreateKind::File {
-            files::recompute_file(&path, &self.config)?;
+            files::recompute_file(&path, index, &self.config)?;
             println!("File created: {:?}", path);
         }
         Ok(())
@@ -32,20 +43,21 @@ impl MindmapWatcher {
         &self,
         kind: notify::event::ModifyKind,
         paths: Vec<PathBuf>,
+        index: &mut VectorIndex,
     ) -> Result<()> {
         let path_str = paths.first().expect("Path should exist");
         let path = PathBuf::from(path_str);
         match kind {
             notify::event::ModifyKind::Data(_data) => {
-                files::recompute_file(&path, &self.config)?;
+                files::recompute_file(&path, index, &self.config)?;
                 println!("File modified: {:?}", path);
             }
             notify::event::ModifyKind::Name(_name) => {
                 if path.exists() {
-                    files::recompute_file(&path, &self.config)?;
+                    files::recompute_file(&path, index, &self.config)?;
                     println!("File renamed: {:?}", path);
                 } else {
-                    files::delete_file(&path, &self.config)?;
+                    files::delete_file(&path, index, &self.config)?;
                     println!("File deleted: {:?}", path);
                 }
             }
@@ -58,47 +70,142 @@ impl MindmapWatcher {
         &self,
         kind: notify::event::RemoveKind,
         paths: Vec<PathBuf>,
+        index: &mut VectorIndex,
     ) -> Result<()> {
         let path_str = paths.first().expect("Path should exist");
         let path = PathBuf::from(path_str);
         if kind == notify::event::RemoveKind::File {
             println!("File removed: {:?}", path);
-            files::delete_file(&path, &self.config)?;
+            files::delete_file(&path, index, &self.config)?;
         }
         Ok(())
     }
 
-    pub fn handle_event(&self, event: Event) -> Result<()> {
+    /// Maps a path inside a watched symlink target back to the path through the link
+    fn logical_path(&self, path: PathBuf) -> PathBuf {
+        for (target, link) in &self.symlinks {
+            if let Ok(rest) = path.strip_prefix(target) {
+                return link.join(rest);
+            }
+        }
+        path
+    }
+
+    /// The most specific root containing a path, with its filter
+    fn root_of(&self, path: &Path) -> Option<&(RootConfig, PathFilter)> {
+        self.roots
+            .iter()
+            .filter(|(root, _)| path.starts_with(&root.path))
+            .max_by_key(|(root, _)| root.path.components().count())
+    }
+
+    fn is_ignored(&self, path: &Path) -> bool {
+        match self.root_of(path) {
+            Some((root, filter)) => {
+                filter.is_ignored(path, path.is_dir()) || Self::behind_symlink(root, path)
+            }
+            None => true,
+        }
+    }
+
+    /// Whether a path goes through a symlinked directory of a root that doesn't follow
+    /// them. inotify reports those anyway, while the walker skips them.
+    fn behind_symlink(root: &RootConfig, path: &Path) -> bool {
+        !root.follow_symlinks
+            && path
+                .ancestors()
+                .skip(1)
+                .take_while(|dir| *dir != root.path)
+                .any(|dir| dir.is_symlink())
+    }
+
+    pub fn handle_event(&self, mut event: Event, index: &mut VectorIndex) -> Result<()> {
+        event.paths = event
+            .paths
+            .into_iter()
+            .map(|path| self.logical_path(path))
+            .collect();
+
+        if let Some(path) = event.paths.first() {
+            if PathFilter::is_ignore_file(path) {
+                self.roots
+                    .iter()
+                    .for_each(|(_, filter)| filter.clear_cache());
+                return Ok(());
+            }
+            if self.is_ignored(path) {
+                return Ok(());
+            }
+        }
+
         match event.kind {
-            EventKind::Create(kind) => self.handle_create_event(kind, event.paths),
-            EventKind::Modify(kind) => self.handle_modify_event(kind, event.paths),
-            EventKind::Remove(kind) => self.handle_remove_event(kind, event.paths),
+            EventKind::Create(kind) => self.handle_create_event(kind, event.paths, index),
+            EventKind::Modify(kind) => self.handle_modify_event(kind, event.paths, index),
+            EventKind::Remove(kind) => self.handle_remove_event(kind, event.paths, index),
             _ => Ok(()),
         }
     }
 
-    pub fn new(config: MindmapConfig) -> Self {
+    pub fn new(config: MindmapConfig) -> Result<Self> {
         let (tx, rx) = std::sync::mpsc::channel();
         let watcher =
             RecommendedWatcher::new(tx, Config::default()).expect("Failed to create watcher");
-        Self {
+        let roots = config
+            .get_roots()
+            .into_iter()
+            .map(|root| {
+                let filter = PathFilter::for_root(&root, &config)?;
+                Ok((root, filter))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
             watcher,
             rx,
             config,
-        }
+            roots,
+            symlinks: vec![],
+        })
+    }
+
+    /// Finds the symlinked directories under a root and where they point to
+    fn find_symlinks(root: &RootConfig) -> Vec<(PathBuf, PathBuf)> {
+        WalkDir::new(&root.path)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.path_is_symlink() && e.path().is_dir())
+            .filter_map(|e| {
+                let target = std::fs::canonicalize(e.path()).ok()?;
+                Some((target, e.into_path()))
+            })
+            .collect()
     }
 
     pub fn watch(&mut self) -> Result<()> {
         utils::acquire_lock(&self.config.watcher.lock_path)?;
 
+        // Loaded once, and reloaded only when another process changes the database
+        let mut index = VectorIndex::open(&self.config)?;
         println!("{}", "Watching files...".blue());
-        self.watcher
-            .watch(&self.config.data_dir, RecursiveMode::Recursive)?;
+        for (root, _) in &self.roots {
+            self.watcher.watch(&root.path, RecursiveMode::Recursive)?;
+
+            // inotify already follows symlinks when watching recursively, other
+            // backends need the targets watched on their own
+            if root.follow_symlinks && !cfg!(target_os = "linux") {
+                for (target, link) in Self::find_symlinks(root) {
+                    self.watcher.watch(&target, RecursiveMode::Recursive)?;
+                    self.symlinks.push((target, link));
+                }
+            }
+        }
 
         for res in &self.rx {
             match res {
                 Ok(event) => {
-                    let _ = &self.handle_event(event);
+                    if let Err(err) = self.handle_event(event, &mut index) {
+                        log::error!("Failed to handle event: {:#}", err);
+                        println!("{}: {:#}", "Error".red(), err);
+                    }
                 }
                 Err(error) => println!("Error: {:?}", error),
             }